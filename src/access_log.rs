@@ -0,0 +1,186 @@
+// One line per answered request on stdout, or in the file given with --access-log,
+// in the format picked with --log-format. Requests for paths under an
+// `--access-log-exclude` prefix, health checks and the like, are left out.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{date, find_header, ProgArgs};
+
+#[derive(Clone, Copy)]
+pub(crate) enum LogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+pub(crate) struct AccessEntry<'a> {
+    pub(crate) remote: Option<IpAddr>,
+    pub(crate) request_line: &'a str,
+    pub(crate) headers: &'a [String],
+    pub(crate) status: u16,
+    pub(crate) bytes: u64,
+}
+
+// Apache writes a missing header as a quoted dash, log parsers expect that
+fn quoted_or_dash(value: Option<&str>) -> String {
+    format!("\"{}\"", value.unwrap_or("-").replace('"', "\\\""))
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn format_entry(format: LogFormat, entry: &AccessEntry) -> String {
+    let remote = entry
+        .remote
+        .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    let request_line = entry.request_line.trim();
+    let referer = find_header(entry.headers, "Referer");
+    let user_agent = find_header(entry.headers, "User-Agent");
+    let now = SystemTime::now();
+
+    match format {
+        LogFormat::Common | LogFormat::Combined => {
+            let bytes = match entry.bytes {
+                0 => "-".to_string(),
+                n => n.to_string(),
+            };
+            let mut line = format!(
+                "{} - - [{}] {} {} {}",
+                remote,
+                date::clf(now),
+                quoted_or_dash(Some(request_line)),
+                entry.status,
+                bytes
+            );
+            if let LogFormat::Combined = format {
+                line.push_str(&format!(
+                    " {} {}",
+                    quoted_or_dash(referer),
+                    quoted_or_dash(user_agent)
+                ));
+            }
+            line
+        }
+        LogFormat::Json => format!(
+            "{{\"remote\":{},\"time\":{},\"request\":{},\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{}}}",
+            json_string(&remote),
+            json_string(&date::clf(now)),
+            json_string(request_line),
+            entry.status,
+            entry.bytes,
+            referer.map_or_else(|| "null".to_string(), json_string),
+            user_agent.map_or_else(|| "null".to_string(), json_string),
+        ),
+    }
+}
+
+/// An access log file that is rotated once it would grow past `max_size`: it
+/// becomes `<path>.1`, older ones move up to `<path>.<keep>` and the oldest is
+/// dropped.
+pub(crate) struct LogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    keep: usize,
+    // the open file and its size, every connection thread logs through it
+    current: Mutex<(File, u64)>,
+}
+
+fn open_append(p: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(p)?;
+    let len = file.metadata()?.len();
+    Ok((file, len))
+}
+
+impl LogFile {
+    pub(crate) fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> io::Result<Self> {
+        let current = open_append(&path)?;
+        Ok(LogFile {
+            path,
+            max_size,
+            keep,
+            current: Mutex::new(current),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> io::Result<(File, u64)> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let older = self.rotated(n);
+                if older.exists() {
+                    fs::rename(&older, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        open_append(&self.path)
+    }
+
+    pub(crate) fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut current = match self.current.lock() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let len = line.len() as u64 + 1;
+        // a line longer than the limit still goes to a file of its own
+        if self
+            .max_size
+            .is_some_and(|max| current.1 > 0 && current.1 + len > max)
+        {
+            *current = self.rotate()?;
+        }
+        writeln!(current.0, "{}", line)?;
+        current.1 += len;
+        Ok(())
+    }
+}
+
+fn excluded(args: &ProgArgs, request_line: &str) -> bool {
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    args.access_log_exclude
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+/// Writes the line for `entry` to the `--access-log` file, or to stdout without
+/// one, unless its path is excluded.
+pub(crate) fn write(args: &ProgArgs, format: LogFormat, entry: &AccessEntry) {
+    if excluded(args, entry.request_line) {
+        return;
+    }
+    let line = format_entry(format, entry);
+    match &args.access_log {
+        Some(file) => {
+            if let Err(e) = file.write_line(&line) {
+                eprintln!("Failed to write access log: {}", e);
+            }
+        }
+        None => println!("{}", line),
+    }
+}
@@ -0,0 +1,516 @@
+// Tokio based alternative to the thread-per-connection server, built with the
+// `async` feature. Only the socket I/O is async: responses are still produced by
+// `determine_http_response`, which reads files synchronously, so it runs on the
+// blocking pool (the same place tokio::fs would send each individual read) and
+// files are not read through tokio::fs. Shutdown drains connections just like
+// the blocking server, through the same tracker.
+use std::{
+    io::{self, Write},
+    mem,
+    net::SocketAddrV4,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::{tcp::ReadHalf, TcpListener, TcpStream},
+    runtime,
+    sync::mpsc,
+    task,
+    time::timeout,
+};
+
+use crate::{
+    access_log, admit_client, admit_request,
+    body::{self, BodyError, RequestBody},
+    build_error_response, determine_http_response, enable_tcp_keepalive, is_simple_request,
+    log_context::{self, log_error, RequestId},
+    metrics,
+    shutdown::{self, ConnectionTracker},
+    wants_close, with_request_counts, write_failure, CloseReason, ConnectionStats, Counted,
+    Framing, ProgArgs, ReadRequestInitialError, RequestHead, Response, MAX_REQUESTS_PER_CONNECTION,
+};
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.bytes += (buf.filled().len() - before) as u64;
+        polled
+    }
+}
+
+pub(crate) fn run(args: Arc<ProgArgs>, listener: std::net::TcpListener, saddr: SocketAddrV4) {
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(size) = args.thread_stack_size {
+        builder.thread_stack_size(size);
+    }
+    let rt = match builder.build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let tracker = Arc::new(ConnectionTracker::default());
+    rt.block_on(accept_loop(
+        Arc::clone(&args),
+        listener,
+        saddr,
+        Arc::clone(&tracker),
+    ));
+    // the connection tasks keep running on the runtime's workers meanwhile
+    shutdown::finish(&tracker, args.shutdown_timeout);
+    // responses still being produced for force-closed connections are not waited for
+    rt.shutdown_background();
+}
+
+async fn accept_loop(
+    args: Arc<ProgArgs>,
+    listener: std::net::TcpListener,
+    saddr: SocketAddrV4,
+    tracker: Arc<ConnectionTracker>,
+) {
+    let listener = match listener
+        .set_nonblocking(true)
+        .and_then(|_| TcpListener::from_std(listener))
+    {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to register listener with the runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    loop {
+        let accepted = listener.accept().await;
+        // the signal handler's connection, which ends the wait for this check
+        if shutdown::requested() {
+            return;
+        }
+        match &accepted {
+            Ok(_) => metrics::connection_accepted(),
+            Err(_) => metrics::accept_failed(),
+        }
+        match accepted {
+            Ok((stream, _)) if args.once => {
+                handle_connection(Arc::clone(&args), tracker, stream, saddr).await;
+                return;
+            }
+            Ok((stream, _)) => {
+                let (args, tracker) = (Arc::clone(&args), Arc::clone(&tracker));
+                tokio::spawn(handle_connection(args, tracker, stream, saddr));
+            }
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Waits for the first byte of the next request, pipelined requests are usually
+/// in the buffer already.
+async fn await_request(
+    rdr: &mut BufReader<Counted<ReadHalf<'_>>>,
+) -> Result<(), ReadRequestInitialError> {
+    match rdr.fill_buf().await {
+        Ok([]) => Err(ReadRequestInitialError::ClientClosed),
+        Ok(_) => Ok(()),
+        Err(e) => Err(ReadRequestInitialError::Io(e)),
+    }
+}
+
+async fn read_request_line_and_headers(
+    rdr: &mut BufReader<Counted<ReadHalf<'_>>>,
+) -> Result<RequestHead, ReadRequestInitialError> {
+    let mut request_line_str = String::new();
+    match rdr.read_line(&mut request_line_str).await {
+        Ok(0) => return Err(ReadRequestInitialError::ClientClosed),
+        Ok(_) => {
+            if request_line_str.trim().is_empty() {
+                return Err(ReadRequestInitialError::EmptyRequest);
+            }
+        }
+        Err(e) => return Err(ReadRequestInitialError::Io(e)),
+    }
+    let mut bare_lf = !request_line_str.ends_with("\r\n");
+    if is_simple_request(&request_line_str) {
+        return Ok((request_line_str, vec![], bare_lf));
+    }
+
+    let mut actual_headers = Vec::new();
+    loop {
+        let mut header_line = String::new();
+        match rdr.read_line(&mut header_line).await {
+            Ok(0) => break,
+            Ok(_) => {
+                bare_lf |= !header_line.ends_with("\r\n");
+                if header_line.trim().is_empty() {
+                    break;
+                }
+                actual_headers.push(header_line.trim_end_matches(['\r', '\n']).to_string());
+            }
+            Err(e) => return Err(ReadRequestInitialError::HeaderReadIo(e)),
+        }
+    }
+    Ok((request_line_str, actual_headers, bare_lf))
+}
+
+// Serialized responses travel to the connection task in blocks of this size, at
+// most RESPONSE_QUEUE_BLOCKS of them waiting at a time.
+const RESPONSE_BLOCK_SIZE: usize = 64 * 1024;
+const RESPONSE_QUEUE_BLOCKS: usize = 4;
+
+/// The blocking pool's end of a response on its way to the socket. Bodies are
+/// handed over as they are produced, so a large file is never held in memory.
+struct ChannelWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= RESPONSE_BLOCK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let block = mem::replace(&mut self.buf, Vec::with_capacity(RESPONSE_BLOCK_SIZE));
+        // the receiver is only gone once the connection failed
+        self.tx
+            .blocking_send(block)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+    }
+}
+
+fn serialize(response: Response, early_hints: bool, w: &mut ChannelWriter) -> io::Result<u64> {
+    if let Some(hints) = response.early_hints().filter(|_| early_hints) {
+        w.write_all(&hints)?;
+        w.flush()?;
+    }
+    let body_len = response.write_to(w)?;
+    w.flush()?;
+    Ok(body_len)
+}
+
+struct Sent {
+    status: u16,
+    closes: bool,
+    // the last response before the connection closes for the shutdown
+    draining: bool,
+    body_len: u64,
+    written: u64,
+}
+
+/// Builds the response with `produce` on the blocking pool, where bodies may read
+/// the filesystem, and writes it to `out` while it is being serialized.
+/// `served`, with `--debug-headers`, is the request's number on its connection,
+/// `last` tells it is the final one the connection takes, `id` prefixes what is
+/// logged meanwhile.
+async fn respond<F, W>(
+    produce: F,
+    early_hints: bool,
+    served: Option<u32>,
+    last: bool,
+    id: RequestId,
+    out: &mut W,
+) -> Result<Sent, CloseReason>
+where
+    F: FnOnce() -> Response + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let (tx, mut rx) = mpsc::channel(RESPONSE_QUEUE_BLOCKS);
+    let serializer = task::spawn_blocking(move || {
+        let _context = log_context::enter(id);
+        let response = match served {
+            Some(served) => with_request_counts(produce(), served),
+            None => produce(),
+        };
+        // the drain has begun, this response is the last one on the connection
+        let draining = shutdown::requested();
+        let response = match response.closes_connection() {
+            false if draining || last => response.with_header("Connection", "close".to_string()),
+            _ => response,
+        };
+        let (status, closes) = (response.code(), response.closes_connection());
+        let mut writer = ChannelWriter {
+            tx,
+            buf: Vec::with_capacity(RESPONSE_BLOCK_SIZE),
+        };
+        (
+            status,
+            closes,
+            draining,
+            serialize(response, early_hints, &mut writer),
+        )
+    });
+
+    let mut written = 0;
+    let mut write_error = None;
+    while let Some(block) = rx.recv().await {
+        if let Err(e) = out.write_all(&block).await {
+            write_error = Some(e);
+            break;
+        }
+        written += block.len() as u64;
+    }
+    // a serializer still blocked on a full queue fails instead of waiting forever
+    drop(rx);
+
+    let (status, closes, draining, serialized) = serializer.await.map_err(|e| {
+        log_error!(id => "Response task failed: {}. Closing connection.", e);
+        CloseReason::WriteError
+    })?;
+    if let Some(e) = write_error {
+        let _context = log_context::enter(id);
+        return Err(write_failure(e));
+    }
+    let body_len = serialized.map_err(|e| {
+        log_error!(id => "Failed to generate response: {}. Closing connection.", e);
+        CloseReason::WriteError
+    })?;
+    Ok(Sent {
+        status,
+        closes,
+        draining,
+        body_len,
+        written,
+    })
+}
+
+async fn drain(
+    rdr: &mut BufReader<Counted<ReadHalf<'_>>>,
+    body: RequestBody,
+    max_body_size: u64,
+) -> Result<(), BodyError> {
+    match body {
+        RequestBody::Length(len) => {
+            let copied = tokio::io::copy(&mut rdr.take(len), &mut tokio::io::sink())
+                .await
+                .map_err(BodyError::Io)?;
+            if copied < len {
+                return Err(body::short_body());
+            }
+        }
+        RequestBody::Chunked => {
+            let mut total = 0;
+            loop {
+                let mut size_line = String::new();
+                rdr.read_line(&mut size_line).await.map_err(BodyError::Io)?;
+                let size = body::parse_chunk_size(&size_line)?;
+                total = body::add_chunk(total, size, max_body_size)?;
+                if size == 0 {
+                    break;
+                }
+                let copied = tokio::io::copy(&mut rdr.take(size + 2), &mut tokio::io::sink())
+                    .await
+                    .map_err(BodyError::Io)?;
+                if copied < size + 2 {
+                    return Err(body::short_body());
+                }
+            }
+            loop {
+                let mut trailer = String::new();
+                let read = rdr.read_line(&mut trailer).await.map_err(BodyError::Io)?;
+                if read == 0 || trailer.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_connection(
+    args: Arc<ProgArgs>,
+    tracker: Arc<ConnectionTracker>,
+    mut stream: TcpStream,
+    addr: SocketAddrV4,
+) {
+    let mut stats = ConnectionStats::default();
+    let peer = stream.peer_addr().ok().map(|peer| peer.ip());
+    let connection_id = log_context::next_connection();
+    let mut id = RequestId {
+        connection: connection_id,
+        request: 1,
+    };
+    let admitted = {
+        let _context = log_context::enter(id);
+        if let Some(idle) = args.tcp_keepalive {
+            enable_tcp_keepalive(socket2::SockRef::from(&stream), idle);
+        }
+        admit_client(&args, peer)
+    };
+    let _client = match admitted {
+        Ok(slot) => slot,
+        Err(response) => {
+            respond(move || response, false, None, false, id, &mut stream)
+                .await
+                .ok();
+            stream.shutdown().await.ok();
+            return;
+        }
+    };
+    // a blocking handle on the same socket, through which the drain wakes an idle
+    // connection with an end of stream
+    let connection = socket2::SockRef::from(&stream)
+        .try_clone()
+        .ok()
+        .and_then(|handle| tracker.register(&handle.into()));
+    let (read_half, mut write_half) = stream.split();
+    let mut rdr = BufReader::new(Counted::new(read_half));
+
+    let reason = loop {
+        id.request = stats.requests + 1;
+        if shutdown::requested() {
+            break CloseReason::Shutdown;
+        }
+        let waited = timeout(args.keep_alive_timeout, await_request(&mut rdr))
+            .await
+            .unwrap_or(Err(ReadRequestInitialError::Timeout));
+        // the rest of the request, its body included, gets the shorter timeout
+        let head = match waited {
+            Ok(()) => timeout(
+                args.request_timeout,
+                read_request_line_and_headers(&mut rdr),
+            )
+            .await
+            .unwrap_or(Err(ReadRequestInitialError::Timeout)),
+            Err(e) => Err(e),
+        };
+        stats.bytes_read = rdr.get_ref().bytes;
+        let (request_line_str, actual_headers, bare_lf) = match head {
+            Ok(parts) => parts,
+            // idle connections are woken up with an end of stream by the drain
+            Err(_) if shutdown::requested() => break CloseReason::Shutdown,
+            Err(ReadRequestInitialError::Timeout) => break CloseReason::Timeout,
+            Err(ReadRequestInitialError::ClientClosed)
+            | Err(ReadRequestInitialError::EmptyRequest) => break CloseReason::ClientClosed,
+            Err(ReadRequestInitialError::Io(e)) => {
+                log_error!(id => "Failed to read request line: {}. Closing connection.", e);
+                break CloseReason::ReadError;
+            }
+            Err(ReadRequestInitialError::HeaderReadIo(e)) => {
+                log_error!(id => "Error reading headers: {}. Closing connection.", e);
+                break CloseReason::ReadError;
+            }
+        };
+
+        let served = args.debug_headers.then_some(stats.requests + 1);
+        let last = stats.requests + 1 >= MAX_REQUESTS_PER_CONNECTION;
+        let _request = tracker.begin_request(connection.as_ref());
+        let admitted = {
+            let _context = log_context::enter(id);
+            admit_request(&args, bare_lf)
+        };
+        let sent = match admitted {
+            Ok(()) => {
+                let drained = match body::request_body(&actual_headers, args.max_body_size) {
+                    Ok(request_body) => {
+                        if body::expects_continue(&actual_headers) && !request_body.is_empty() {
+                            if let Err(e) = write_half.write_all(body::CONTINUE).await {
+                                let _context = log_context::enter(id);
+                                break write_failure(e);
+                            }
+                            stats.bytes_written += body::CONTINUE.len() as u64;
+                        }
+                        // a client that never sends the body it announced must not hold the task
+                        timeout(
+                            args.request_timeout,
+                            drain(&mut rdr, request_body, args.max_body_size),
+                        )
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(BodyError::Io(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "request body not received",
+                            )))
+                        })
+                    }
+                    Err(status) => Err(BodyError::Rejected(status)),
+                };
+                stats.bytes_read = rdr.get_ref().bytes;
+                match drained {
+                    Ok(()) => {
+                        let args = Arc::clone(&args);
+                        let early_hints = args.early_hints;
+                        let (line, headers) = (request_line_str.clone(), actual_headers.clone());
+                        let produce = move || determine_http_response(&line, &headers, &args, addr);
+                        respond(produce, early_hints, served, last, id, &mut write_half).await
+                    }
+                    Err(BodyError::Rejected(status)) => {
+                        let produce = move || build_error_response(status, Framing::Unsafe);
+                        respond(produce, false, served, last, id, &mut write_half).await
+                    }
+                    Err(BodyError::Io(e)) => {
+                        log_error!(id => "Failed to drain request body: {}. Closing connection.", e);
+                        break CloseReason::ReadError;
+                    }
+                }
+            }
+            // the connection closes, its body is never read
+            Err(response) => {
+                respond(move || response, false, served, last, id, &mut write_half).await
+            }
+        };
+        let Sent {
+            status,
+            closes,
+            draining,
+            body_len,
+            written,
+        } = match sent {
+            Ok(sent) => sent,
+            Err(reason) => break reason,
+        };
+        stats.bytes_written += written;
+
+        let wants_close = closes || wants_close(&actual_headers);
+
+        if let Some(format) = args.log_format {
+            let entry = access_log::AccessEntry {
+                remote: peer,
+                request_line: &request_line_str,
+                headers: &actual_headers,
+                status,
+                bytes: body_len,
+            };
+            access_log::write(&args, format, &entry);
+        }
+
+        stats.requests += 1;
+        if draining {
+            break CloseReason::Shutdown;
+        }
+        if last {
+            break CloseReason::MaxRequests;
+        }
+        if wants_close {
+            break CloseReason::ConnectionClose;
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    println!("{}", stats.summary(addr, reason));
+    // the peer already tore the connection down, a shutdown would only fail noisily
+    if !matches!(reason, CloseReason::ClientAborted) {
+        if let Err(e) = write_half.shutdown().await {
+            if e.kind() != io::ErrorKind::NotConnected {
+                log_error!(id => "Failed to shutdown stream: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,281 @@
+// Request body framing. Bodies are never used by the server, they are only
+// consumed so the next request on the connection starts at the right byte.
+use crate::{find_header, Status};
+#[cfg(not(feature = "async"))]
+use std::io::{self, BufRead, Read};
+
+pub(crate) enum RequestBody {
+    Length(u64),
+    Chunked,
+}
+
+impl RequestBody {
+    pub(crate) fn is_empty(&self) -> bool {
+        matches!(self, RequestBody::Length(0))
+    }
+}
+
+/// Interim response telling a client that waits on `Expect: 100-continue` to
+/// go ahead and send the body.
+pub(crate) const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Whether the client holds its body back until it sees `CONTINUE`. Other
+/// expectations are refused by `request_body`.
+pub(crate) fn expects_continue(actual_headers: &[String]) -> bool {
+    find_header(actual_headers, "Expect")
+        .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+}
+
+// A body cut short by the client leaves the connection mid-message, it cannot be
+// reused for another request.
+pub(crate) fn short_body() -> BodyError {
+    BodyError::Io(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "request body ended early",
+    ))
+}
+
+pub(crate) enum BodyError {
+    // answered with this status and the connection is closed afterwards
+    Rejected(Status),
+    Io(std::io::Error),
+}
+
+/// Works out how the body of a request is delimited, rejecting ambiguous or
+/// oversized declarations, and expectations other than 100-continue, before a
+/// single body byte is read.
+pub(crate) fn request_body(
+    actual_headers: &[String],
+    max_body_size: u64,
+) -> Result<RequestBody, Status> {
+    if find_header(actual_headers, "Expect")
+        .is_some_and(|value| !value.is_empty() && !value.eq_ignore_ascii_case("100-continue"))
+    {
+        return Err(Status::ExpectationFailed);
+    }
+    if let Some(encoding) = find_header(actual_headers, "Transfer-Encoding") {
+        // a proxy in front may have framed the body by the other one, and passed
+        // on what is a second request here
+        if find_header(actual_headers, "Content-Length").is_some() {
+            return Err(Status::BadRequest);
+        }
+        let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+        return if last.eq_ignore_ascii_case("chunked") {
+            Ok(RequestBody::Chunked)
+        } else {
+            // without chunked last the body would run until the connection closes
+            Err(Status::BadRequest)
+        };
+    }
+
+    let mut declared = None;
+    for header in actual_headers {
+        let (name, value) = match header.split_once(':') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if !name.trim().eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        for value in value.split(',').map(str::trim) {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Status::BadRequest);
+            }
+            let len: u64 = value.parse().map_err(|_| Status::BadRequest)?;
+            match declared {
+                Some(previous) if previous != len => return Err(Status::BadRequest),
+                _ => declared = Some(len),
+            }
+        }
+    }
+
+    match declared.unwrap_or(0) {
+        len if len > max_body_size => Err(Status::PayloadTooLarge),
+        len => Ok(RequestBody::Length(len)),
+    }
+}
+
+/// Parses a chunk-size line, ignoring chunk extensions.
+pub(crate) fn parse_chunk_size(line: &str) -> Result<u64, BodyError> {
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16).map_err(|_| BodyError::Rejected(Status::BadRequest))
+}
+
+/// Adds a chunk to the running total, failing once the cap is exceeded.
+pub(crate) fn add_chunk(total: u64, chunk: u64, max_body_size: u64) -> Result<u64, BodyError> {
+    match total.checked_add(chunk) {
+        Some(total) if total <= max_body_size => Ok(total),
+        _ => Err(BodyError::Rejected(Status::PayloadTooLarge)),
+    }
+}
+
+#[cfg(not(feature = "async"))]
+pub(crate) fn drain<R: BufRead>(
+    rdr: &mut R,
+    body: RequestBody,
+    max_body_size: u64,
+) -> Result<(), BodyError> {
+    match body {
+        RequestBody::Length(len) => {
+            if io::copy(&mut rdr.take(len), &mut io::sink()).map_err(BodyError::Io)? < len {
+                return Err(short_body());
+            }
+        }
+        RequestBody::Chunked => {
+            let mut total = 0;
+            loop {
+                let mut size_line = String::new();
+                rdr.read_line(&mut size_line).map_err(BodyError::Io)?;
+                let size = parse_chunk_size(&size_line)?;
+                total = add_chunk(total, size, max_body_size)?;
+                if size == 0 {
+                    break;
+                }
+                // chunk data plus its trailing CRLF
+                if io::copy(&mut rdr.take(size + 2), &mut io::sink()).map_err(BodyError::Io)?
+                    < size + 2
+                {
+                    return Err(short_body());
+                }
+            }
+            // trailer section, ends with an empty line
+            loop {
+                let mut trailer = String::new();
+                if rdr.read_line(&mut trailer).map_err(BodyError::Io)? == 0
+                    || trailer.trim().is_empty()
+                {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u64 = 1 << 20;
+
+    fn framing(lines: &[&str]) -> Result<RequestBody, Status> {
+        let headers: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        request_body(&headers, MAX)
+    }
+
+    #[test]
+    fn length_and_chunked_together_are_rejected() {
+        for lines in [
+            ["Content-Length: 5", "Transfer-Encoding: chunked"],
+            ["Transfer-Encoding: chunked", "content-length: 0"],
+        ] {
+            assert!(matches!(framing(&lines), Err(Status::BadRequest)));
+        }
+    }
+
+    #[test]
+    fn conflicting_lengths_are_rejected() {
+        let conflicting = framing(&["Content-Length: 5", "Content-Length: 6"]);
+        assert!(matches!(conflicting, Err(Status::BadRequest)));
+        assert!(matches!(
+            framing(&["Content-Length: 5, 6"]),
+            Err(Status::BadRequest)
+        ));
+        assert!(matches!(
+            framing(&["Content-Length: +5"]),
+            Err(Status::BadRequest)
+        ));
+        assert!(matches!(
+            framing(&["Content-Length:"]),
+            Err(Status::BadRequest)
+        ));
+        // the same length repeated says nothing new
+        let repeated = framing(&["Content-Length: 5", "Content-Length: 5, 5"]);
+        assert!(matches!(repeated, Ok(RequestBody::Length(5))));
+    }
+
+    #[test]
+    fn chunked_must_be_the_last_coding() {
+        let chunked = framing(&["Transfer-Encoding: gzip, chunked"]);
+        assert!(matches!(chunked, Ok(RequestBody::Chunked)));
+        let unframed = framing(&["Transfer-Encoding: chunked, gzip"]);
+        assert!(matches!(unframed, Err(Status::BadRequest)));
+    }
+
+    #[test]
+    fn declared_length_over_the_cap_is_413() {
+        let over = framing(&["Content-Length: 9999999999"]);
+        assert!(matches!(over, Err(Status::PayloadTooLarge)));
+        let at = framing(&["Content-Length: 1048576"]);
+        assert!(matches!(at, Ok(RequestBody::Length(MAX))));
+    }
+
+    #[test]
+    fn chunks_count_against_the_cap() {
+        assert!(matches!(add_chunk(0, MAX, MAX), Ok(MAX)));
+        let over = add_chunk(MAX, 1, MAX);
+        assert!(matches!(
+            over,
+            Err(BodyError::Rejected(Status::PayloadTooLarge))
+        ));
+        let overflow = add_chunk(1, u64::MAX, u64::MAX);
+        assert!(matches!(
+            overflow,
+            Err(BodyError::Rejected(Status::PayloadTooLarge))
+        ));
+    }
+
+    // what is left to read once the body is drained
+    #[cfg(not(feature = "async"))]
+    fn after_drain(wire: &[u8], body: RequestBody, max: u64) -> Result<Vec<u8>, BodyError> {
+        let mut rdr = io::Cursor::new(wire);
+        drain(&mut rdr, body, max)?;
+        let mut rest = vec![];
+        rdr.read_to_end(&mut rest).map_err(BodyError::Io)?;
+        Ok(rest)
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn drain_stops_at_the_next_request() {
+        let next = b"GET / HTTP/1.1\r\n";
+        let sized = after_drain(
+            b"0123456789GET / HTTP/1.1\r\n",
+            RequestBody::Length(10),
+            MAX,
+        );
+        assert!(sized.is_ok_and(|rest| rest == next));
+        let chunked = after_drain(
+            b"4;ext=1\r\nabcd\r\n6\r\nefghij\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1\r\n",
+            RequestBody::Chunked,
+            10,
+        );
+        assert!(chunked.is_ok_and(|rest| rest == next));
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn drain_enforces_the_cap_and_the_length() {
+        let over = after_drain(
+            b"4\r\nabcd\r\n6\r\nefghij\r\n0\r\n\r\n",
+            RequestBody::Chunked,
+            8,
+        );
+        assert!(matches!(
+            over,
+            Err(BodyError::Rejected(Status::PayloadTooLarge))
+        ));
+        let short = after_drain(b"0123", RequestBody::Length(10), MAX);
+        assert!(matches!(short, Err(BodyError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+        let bad_size = after_drain(b"zz\r\n", RequestBody::Chunked, MAX);
+        assert!(matches!(
+            bad_size,
+            Err(BodyError::Rejected(Status::BadRequest))
+        ));
+    }
+
+    #[test]
+    fn no_framing_is_an_empty_body() {
+        assert!(framing(&["Host: localhost"]).is_ok_and(|body| body.is_empty()));
+    }
+}
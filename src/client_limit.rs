@@ -0,0 +1,48 @@
+// Per-client connection cap (`--max-connections-per-ip`), so one client holding
+// many keep-alive connections cannot take every worker for itself.
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+pub(crate) struct ClientLimit {
+    max: usize,
+    // only clients with at least one open connection have an entry
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// One open connection counted against its client, released on drop.
+pub(crate) struct ClientSlot<'a> {
+    limit: &'a ClientLimit,
+    ip: IpAddr,
+}
+
+impl ClientLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        ClientLimit {
+            max,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// None when `ip` already has the maximum number of connections open.
+    pub(crate) fn acquire(&self, ip: IpAddr) -> Option<ClientSlot<'_>> {
+        let mut open = self.open.lock().ok()?;
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot { limit: self, ip })
+    }
+}
+
+impl Drop for ClientSlot<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.limit.open.lock() {
+            if let Some(count) = open.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
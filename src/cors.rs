@@ -0,0 +1,95 @@
+// Cross-origin access (`--cors-origin`): responses to an allowed Origin carry
+// Access-Control-Allow-Origin, and preflights, OPTIONS requests naming the
+// method a script wants to use, are answered with the method and headers asked
+// about, for browsers to cache for `--cors-preflight-max-age` seconds. With
+// `--cors-credentials` scripts may also send cookies, and the Origin is then
+// always echoed, as browsers refuse credentialed responses allowed to `*`.
+use crate::{find_header, is_token_char, Body, Response, Status};
+
+pub(crate) struct Cors {
+    // a "*" entry allows every origin
+    origins: Vec<String>,
+    max_age: u64,
+    credentials: bool,
+}
+
+impl Cors {
+    pub(crate) fn new(origins: Vec<String>, max_age: u64, credentials: bool) -> Self {
+        Cors {
+            origins,
+            max_age,
+            credentials,
+        }
+    }
+
+    /// Access-Control-Allow-Origin for a request from `origin`, if it is allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            return Some(match self.credentials {
+                true => origin.to_string(),
+                false => "*".to_string(),
+            });
+        }
+        self.origins
+            .iter()
+            .find(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .map(|_| origin.to_string())
+    }
+
+    pub(crate) fn is_preflight(&self, req_headers: &[String]) -> bool {
+        find_header(req_headers, "Origin").is_some()
+            && find_header(req_headers, "Access-Control-Request-Method").is_some()
+    }
+
+    /// The 204 answering a preflight. The requested method is only confirmed when
+    /// it is one of `allowed`, the requested headers only when they are all field
+    /// names, anything else leaves the browser to refuse the real request.
+    pub(crate) fn preflight(&self, req_headers: &[String], allowed: String) -> Response {
+        let mut response = Response {
+            status: Status::NoContent,
+            headers: vec![],
+            body: Body::None,
+        };
+        let origin_allowed = find_header(req_headers, "Origin")
+            .and_then(|origin| self.allow_origin(origin))
+            .is_some();
+        let method = find_header(req_headers, "Access-Control-Request-Method")
+            .filter(|method| allowed.split(", ").any(|allowed| allowed == *method));
+        if let Some(method) = method.filter(|_| origin_allowed) {
+            response = response
+                .with_header("Access-Control-Allow-Methods", method.to_string())
+                .with_header("Access-Control-Max-Age", self.max_age.to_string());
+            let requested =
+                find_header(req_headers, "Access-Control-Request-Headers").filter(|requested| {
+                    requested
+                        .split(',')
+                        .map(str::trim)
+                        .all(|name| !name.is_empty() && name.bytes().all(is_token_char))
+                });
+            if let Some(requested) = requested {
+                response =
+                    response.with_header("Access-Control-Allow-Headers", requested.to_string());
+            }
+        }
+        response.with_header("Allow", allowed)
+    }
+
+    /// Adds Access-Control-Allow-Origin to the response for an allowed Origin.
+    pub(crate) fn apply(&self, response: Response, req_headers: &[String]) -> Response {
+        let allow_origin =
+            match find_header(req_headers, "Origin").and_then(|origin| self.allow_origin(origin)) {
+                Some(allow_origin) => allow_origin,
+                None => return response,
+            };
+        // a reflected origin differs between requests, caches must keep them apart
+        let response = match allow_origin.as_str() {
+            "*" => response,
+            _ => response.with_vary("Origin"),
+        };
+        let response = response.with_header("Access-Control-Allow-Origin", allow_origin);
+        match self.credentials {
+            true => response.with_header("Access-Control-Allow-Credentials", "true".to_string()),
+            false => response,
+        }
+    }
+}
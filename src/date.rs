@@ -0,0 +1,173 @@
+// Calendar conversion for log and header timestamps, all in UTC. Uses the
+// days-from-civil algorithm by Howard Hinnant to avoid a date dependency.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 1970-01-01 was a Thursday
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+struct Civil {
+    year: i64,
+    month: usize,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+fn civil_from_system_time(t: SystemTime) -> Civil {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as usize;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    Civil {
+        year,
+        month,
+        day,
+        hour: rem / 3_600,
+        minute: rem % 3_600 / 60,
+        second: rem % 60,
+    }
+}
+
+// inverse of the conversion above, for dates that are already known to be valid
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// HTTP date in the IMF-fixdate form, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(t: SystemTime) -> String {
+    let c = civil_from_system_time(t);
+    let days = t
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86_400);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        c.day,
+        MONTHS[c.month - 1],
+        c.year,
+        c.hour,
+        c.minute,
+        c.second
+    )
+}
+
+// Four digits, as IMF-fixdate writes them. Anything past this would only be a
+// client trying to overflow the arithmetic below.
+const MAX_YEAR: i64 = 9999;
+
+/// Parses an IMF-fixdate. The obsolete RFC 850 and asctime forms are not
+/// accepted, a conditional header that cannot be parsed is simply ignored.
+pub(crate) fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let (_weekday, rest) = s.trim().split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| m == month)? as i64 + 1;
+    let year: i64 = year
+        .parse()
+        .ok()
+        .filter(|year| (1..=MAX_YEAR).contains(year))?;
+    let hms: Vec<u64> = time
+        .split(':')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let (hour, minute, second) = match hms.as_slice() {
+        [h, m, s] if *h < 24 && *m < 60 && *s < 61 => (*h, *m, *s),
+        _ => return None,
+    };
+    if !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3_600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Common Log Format timestamp, e.g. `10/Oct/2000:13:55:36 +0000`.
+pub(crate) fn clf(t: SystemTime) -> String {
+    let c = civil_from_system_time(t);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        c.day,
+        MONTHS[c.month - 1],
+        c.year,
+        c.hour,
+        c.minute,
+        c.second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let t = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(t));
+        let last = parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").unwrap();
+        assert_eq!(http_date(last), "Fri, 31 Dec 9999 23:59:59 GMT");
+    }
+
+    #[test]
+    fn before_the_epoch() {
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 01 Jan 0001 00:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn huge_years() {
+        for date in [
+            "Sun, 06 Nov 10000 08:49:37 GMT",
+            "Sun, 06 Nov 400000000000 08:49:37 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov 99999999999999999999 08:49:37 GMT",
+            "Sun, 06 Nov -400000000000 08:49:37 GMT",
+            "Sun, 06 Nov 0 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{:?}", date);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Accept-Encoding negotiation (RFC 9110, section 12.5.3) between the codings a
+// client accepts and the ones a resource is available in.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+/// Codings a file may have a precompressed sibling in, the better compression
+/// first so that it wins when the client has no preference.
+pub(crate) const PRECOMPRESSED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+impl Encoding {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// The extension added to a file name for its precompressed sibling.
+    pub(crate) fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Identity => None,
+        }
+    }
+}
+
+/// A qvalue in thousandths: "0" to "1" with at most three decimals.
+pub(crate) fn parse_qvalue(q: &str) -> Option<u16> {
+    let (int, frac) = q.split_once('.').unwrap_or((q, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac: u16 = format!("{:0<3}", frac).parse().ok()?;
+    match int {
+        "0" => Some(frac),
+        "1" if frac == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// (coding, weight) for every well-formed entry of an Accept-Encoding value. A
+/// coding with an unparseable weight is left out, as if it had not been sent.
+fn weighted_codings(header: &str) -> Vec<(&str, u16)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let coding = params.next().filter(|coding| !coding.is_empty())?;
+            let mut weight = 1000;
+            for param in params {
+                match param.split_once('=') {
+                    Some((name, q)) if name.trim().eq_ignore_ascii_case("q") => {
+                        weight = parse_qvalue(q.trim())?;
+                    }
+                    _ => {}
+                }
+            }
+            Some((coding, weight))
+        })
+        .collect()
+}
+
+/// Picks the coding to send from `available`, in order of preference, given the
+/// request's Accept-Encoding. None when every available coding was refused and a
+/// 406 is due. Without the header any coding is allowed, but one the client may
+/// not decode is only sent when there is nothing else.
+pub(crate) fn negotiate_encoding(header: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let header = match header {
+        Some(header) => header,
+        None => {
+            return available
+                .iter()
+                .copied()
+                .find(|encoding| *encoding == Encoding::Identity)
+                .or_else(|| available.first().copied())
+        }
+    };
+    let codings = weighted_codings(header);
+    let weight_of = |name: &str| {
+        codings
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+            .map(|(_, weight)| *weight)
+    };
+    let wildcard = weight_of("*");
+    let mut best: Option<(Encoding, u16)> = None;
+    for &encoding in available {
+        // identity is acceptable unless refused explicitly or through `*`
+        let unlisted = match encoding {
+            Encoding::Identity => 1,
+            _ => 0,
+        };
+        let weight = weight_of(encoding.as_str())
+            .or(wildcard)
+            .unwrap_or(unlisted);
+        // a weight of 0 means refused, ties go to the earlier, preferred coding
+        if weight > best.map_or(0, |(_, best)| best) {
+            best = Some((encoding, weight));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+    fn negotiate(header: &str, available: &[Encoding]) -> Option<&'static str> {
+        negotiate_encoding(Some(header), available).map(Encoding::as_str)
+    }
+
+    #[test]
+    fn qvalues() {
+        assert_eq!(parse_qvalue("0"), Some(0));
+        assert_eq!(parse_qvalue("1"), Some(1000));
+        assert_eq!(parse_qvalue("1.000"), Some(1000));
+        assert_eq!(parse_qvalue("0.5"), Some(500));
+        assert_eq!(parse_qvalue("0.05"), Some(50));
+        assert_eq!(parse_qvalue("0.001"), Some(1));
+        assert_eq!(parse_qvalue("0."), Some(0));
+    }
+
+    #[test]
+    fn malformed_qvalues() {
+        assert_eq!(parse_qvalue("1.0001"), None);
+        assert_eq!(parse_qvalue("0.1234"), None);
+        assert_eq!(parse_qvalue("1.5"), None);
+        assert_eq!(parse_qvalue("2"), None);
+        assert_eq!(parse_qvalue("abc"), None);
+        assert_eq!(parse_qvalue("0.a"), None);
+        assert_eq!(parse_qvalue("-0"), None);
+        assert_eq!(parse_qvalue(""), None);
+    }
+
+    #[test]
+    fn no_header_prefers_identity() {
+        let negotiate =
+            |available: &[Encoding]| negotiate_encoding(None, available).map(Encoding::as_str);
+        assert_eq!(negotiate(&ALL), Some("identity"));
+        // a coding the client may not decode is still better than nothing
+        assert_eq!(negotiate(&[Encoding::Gzip]), Some("gzip"));
+    }
+
+    #[test]
+    fn zero_weight_excludes() {
+        assert_eq!(negotiate("br;q=0, gzip", &ALL), Some("gzip"));
+        assert_eq!(negotiate("br;q=0, gzip;q=0", &ALL), Some("identity"));
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(negotiate("*", &ALL), Some("br"));
+        assert_eq!(negotiate("gzip, *;q=0", &ALL), Some("gzip"));
+        // `*` also covers identity when it is not listed
+        assert_eq!(negotiate("*;q=0", &ALL), None);
+        assert_eq!(negotiate("*;q=0, identity", &ALL), Some("identity"));
+    }
+
+    #[test]
+    fn identity_refused() {
+        assert_eq!(negotiate("identity;q=0", &[Encoding::Identity]), None);
+        assert_eq!(
+            negotiate("br, identity;q=0", &[Encoding::Gzip, Encoding::Identity]),
+            None
+        );
+        assert_eq!(
+            negotiate("br", &[Encoding::Gzip, Encoding::Identity]),
+            Some("identity")
+        );
+    }
+
+    #[test]
+    fn malformed_weight_ignores_the_coding() {
+        assert_eq!(negotiate("br;q=1.0001, gzip;q=0.5", &ALL), Some("gzip"));
+        assert_eq!(negotiate("br;q=abc, gzip;q=0.5", &ALL), Some("gzip"));
+        assert_eq!(negotiate("br;q=0.1234", &ALL), Some("identity"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(negotiate("GZIP", &ALL), Some("gzip"));
+        assert_eq!(negotiate("Br;Q=0.9, gzip;q=0.1", &ALL), Some("br"));
+        assert_eq!(negotiate("IDENTITY;q=0", &[Encoding::Identity]), None);
+    }
+
+    #[test]
+    fn ties_go_to_server_preference() {
+        assert_eq!(negotiate("gzip, br", &ALL), Some("br"));
+        assert_eq!(negotiate("gzip;q=0.8, br;q=0.8", &ALL), Some("br"));
+        assert_eq!(negotiate("gzip, br;q=0.5", &ALL), Some("gzip"));
+        assert_eq!(
+            negotiate("gzip, br", &[Encoding::Gzip, Encoding::Brotli]),
+            Some("gzip")
+        );
+    }
+}
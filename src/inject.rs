@@ -0,0 +1,84 @@
+// Snippets spliced into every HTML page served from disk (`--inject-head`,
+// `--inject-body`), e.g. an analytics tag: the head snippet goes before the first
+// `</head>`, the body snippet before the last `</body>`. A page lacking the tag
+// gets the snippet at its end, which browsers still run.
+use std::{fs, io, path::PathBuf};
+
+enum Snippet {
+    // replaced by its contents at startup, requests never read it
+    File(PathBuf),
+    Loaded(Vec<u8>),
+}
+
+impl Snippet {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Snippet::Loaded(bytes) => bytes,
+            // every snippet is loaded before the server starts
+            Snippet::File(_) => &[],
+        }
+    }
+}
+
+pub(crate) struct Inject {
+    head: Option<Snippet>,
+    body: Option<Snippet>,
+}
+
+fn find_tag(page: &[u8], tag: &[u8], last: bool) -> Option<usize> {
+    let mut windows = page.windows(tag.len());
+    match last {
+        true => windows.rposition(|w| w.eq_ignore_ascii_case(tag)),
+        false => windows.position(|w| w.eq_ignore_ascii_case(tag)),
+    }
+}
+
+impl Inject {
+    pub(crate) fn new(head: Option<PathBuf>, body: Option<PathBuf>) -> Self {
+        Inject {
+            head: head.map(Snippet::File),
+            body: body.map(Snippet::File),
+        }
+    }
+
+    pub(crate) fn load(&mut self) -> io::Result<()> {
+        for snippet in [&mut self.head, &mut self.body].into_iter().flatten() {
+            if let Snippet::File(p) = snippet {
+                *snippet = Snippet::Loaded(fs::read(p)?);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.head.is_some() || self.body.is_some()
+    }
+
+    /// How many bytes a page grows by, the same for every page.
+    pub(crate) fn len(&self) -> u64 {
+        [&self.head, &self.body]
+            .into_iter()
+            .flatten()
+            .map(|snippet| snippet.bytes().len() as u64)
+            .sum()
+    }
+
+    pub(crate) fn apply(&self, mut page: Vec<u8>) -> Vec<u8> {
+        // positions in the page as read, spliced from the back so they stay valid,
+        // the body snippet first so that both at the end keep their order
+        let mut splices = vec![];
+        if let Some(body) = &self.body {
+            let at = find_tag(&page, b"</body>", true).unwrap_or(page.len());
+            splices.push((at, body.bytes()));
+        }
+        if let Some(head) = &self.head {
+            let at = find_tag(&page, b"</head>", false).unwrap_or(page.len());
+            splices.push((at, head.bytes()));
+        }
+        splices.sort_by_key(|(at, _)| std::cmp::Reverse(*at));
+        for (at, snippet) in splices {
+            page.splice(at..at, snippet.iter().copied());
+        }
+        page
+    }
+}
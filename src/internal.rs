@@ -0,0 +1,56 @@
+// Built-in endpoints living under a reserved path prefix (`/__` by default).
+// They are answered before any Host or filesystem resolution, so they work for
+// every virtual host and never touch the document root.
+use std::borrow::Cow;
+
+use crate::{build_error_response, build_http_response, metrics, Framing, Response, Status};
+
+/// Something that can answer a request on its own, without the filesystem.
+pub(crate) trait Handler: Send + Sync {
+    fn handle(&self, path: &str, actual_headers: &[String]) -> Response;
+}
+
+struct Health;
+
+impl Handler for Health {
+    fn handle(&self, _path: &str, _actual_headers: &[String]) -> Response {
+        build_http_response(
+            Status::Success,
+            "text/plain; charset=utf-8",
+            Cow::Borrowed(b"ok\n"),
+        )
+        .with_header("Cache-Control", "no-store".to_string())
+    }
+}
+
+pub(crate) struct InternalRouter {
+    prefix: String,
+    routes: Vec<(&'static str, Box<dyn Handler>)>,
+}
+
+impl InternalRouter {
+    pub(crate) fn new(prefix: String) -> Self {
+        let mut router = InternalRouter {
+            prefix,
+            routes: vec![],
+        };
+        router.register("health", Box::new(Health));
+        router.register("metrics", Box::new(metrics::Metrics));
+        router
+    }
+
+    pub(crate) fn register(&mut self, name: &'static str, handler: Box<dyn Handler>) {
+        self.routes.push((name, handler));
+    }
+
+    /// `None` when the path is outside the reserved prefix and belongs to the site.
+    pub(crate) fn route(&self, resource: &str, actual_headers: &[String]) -> Option<Response> {
+        let path = resource.split('?').next().unwrap_or_default();
+        let name = path.strip_prefix(self.prefix.as_str())?;
+        let response = match self.routes.iter().find(|(route, _)| *route == name) {
+            Some((_, handler)) => handler.handle(path, actual_headers),
+            None => build_error_response(Status::PageNotFound, Framing::Safe),
+        };
+        Some(response)
+    }
+}
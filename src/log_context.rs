@@ -0,0 +1,67 @@
+// Which connection and request an error line is about, prefixed as
+// `[conn=<id> req=<seq>]` so that the lines of concurrent connections can be told
+// apart. Responses are built on a single thread, the connection's own or one of
+// the async server's blocking pool, which holds the context for as long as it
+// works on the request.
+use std::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<RequestId>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct RequestId {
+    pub(crate) connection: u64,
+    // counted from 1 on each connection
+    pub(crate) request: u32,
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[conn={} req={}]", self.connection, self.request)
+    }
+}
+
+pub(crate) fn next_connection() -> u64 {
+    NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Makes `id` the context of this thread's log lines until the guard is dropped.
+pub(crate) fn enter(id: RequestId) -> Entered {
+    Entered {
+        previous: CURRENT.with(|current| current.replace(Some(id))),
+    }
+}
+
+pub(crate) struct Entered {
+    previous: Option<RequestId>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+/// `[conn=<id> req=<seq>] ` when the thread is working on a request.
+pub(crate) fn prefix() -> String {
+    CURRENT.with(|current| current.get().map_or(String::new(), |id| format!("{} ", id)))
+}
+
+/// `eprintln!` with the prefix of the request being handled. An async task, which
+/// moves between threads, names its request instead: `log_error!(id => ...)`.
+macro_rules! log_error {
+    ($id:expr => $($arg:tt)*) => {
+        eprintln!("{} {}", $id, format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::log_context::prefix(), format_args!($($arg)*))
+    };
+}
+pub(crate) use log_error;
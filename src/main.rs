@@ -1,22 +1,71 @@
+mod access_log;
+#[cfg(feature = "async")]
+mod async_server;
+mod body;
+mod client_limit;
+mod cors;
+mod date;
+mod encoding;
+mod inject;
+mod internal;
+mod log_context;
+mod maintenance;
+#[cfg(feature = "mmap")]
+mod mapped;
+mod metrics;
+mod preload;
+mod rate_limit;
+mod redirects;
+mod shutdown;
+mod static_routes;
+mod tree_index;
+mod webdav;
+
 use std::{
     borrow::Cow,
     env::{self, Args},
     fs,
-    io::{self, BufRead, BufReader, Write},
-    net::{Ipv4Addr, SocketAddrV4, TcpStream},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log_context::log_error;
+// only the blocking server touches std sockets directly
+#[cfg(not(feature = "async"))]
+use std::{
+    io::{BufRead, BufReader},
+    net::TcpStream,
 };
 
 #[derive(Clone)]
 enum Status {
     Success,
-    MovedPermamently(String),
+    NoContent,
+    PartialContent,
+    Multistatus,
+    // redirects carry their Location
+    MovedPermanently(String),
+    Found(String),
+    NotModified,
+    TemporaryRedirect(String),
+    PermanentRedirect(String),
     BadRequest,
     Forbidden,
     PageNotFound,
+    MethodNotAllowed,
+    // the codings the resource is available in
+    NotAcceptable(String),
+    PayloadTooLarge,
+    ExpectationFailed,
+    RangeNotSatisfiable,
+    MisdirectedRequest,
     InternalServerError,
     NotImplemented,
+    ServiceUnavailable,
 }
 
 // as I'm using the format! macro, the format literal needs to be known at compile time
@@ -29,387 +78,3537 @@ macro_rules! HTML_ERROR {() => (
     "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n</body>\n</html>"
 )}
 
+macro_rules! HTML_ERROR_DETAIL {() => (
+    "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<pre>{1}</pre>\n</body>\n</html>"
+)}
+
+macro_rules! HTML_NOT_ACCEPTABLE {() => (
+    "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<p>Available content codings: {1}.</p>\n</body>\n</html>"
+)}
+
+// the listing is streamed, entries are written between these two halves
+macro_rules! HTML_LISTING_HEAD {() => (
+    "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n"
+)}
+
+const HTML_LISTING_TAIL: &str = "</ul>\n</body>\n</html>";
+
 #[rustfmt::skip]
 fn from_status(s: Status) -> (u16, &'static str) {
     match s {
-        Status::Success             => (200, "OK"),
-        Status::MovedPermamently(_) => (301, "Moved Permamently"),
-        Status::BadRequest          => (400, "Bad Request"),
-        Status::Forbidden           => (403, "Forbidden"),
-        Status::PageNotFound        => (404, "Not Found"),
-        Status::InternalServerError => (500, "Internal Server Error"),
-        Status::NotImplemented      => (501, "Not Implemented"),
+        Status::Success              => (200, "OK"),
+        Status::NoContent            => (204, "No Content"),
+        Status::PartialContent       => (206, "Partial Content"),
+        Status::Multistatus          => (207, "Multi-Status"),
+        Status::MovedPermanently(_)  => (301, "Moved Permanently"),
+        Status::Found(_)             => (302, "Found"),
+        Status::NotModified          => (304, "Not Modified"),
+        Status::TemporaryRedirect(_) => (307, "Temporary Redirect"),
+        Status::PermanentRedirect(_) => (308, "Permanent Redirect"),
+        Status::BadRequest           => (400, "Bad Request"),
+        Status::Forbidden            => (403, "Forbidden"),
+        Status::PageNotFound         => (404, "Not Found"),
+        Status::MethodNotAllowed     => (405, "Method Not Allowed"),
+        Status::NotAcceptable(_)     => (406, "Not Acceptable"),
+        Status::PayloadTooLarge      => (413, "Payload Too Large"),
+        Status::RangeNotSatisfiable  => (416, "Range Not Satisfiable"),
+        Status::ExpectationFailed    => (417, "Expectation Failed"),
+        Status::MisdirectedRequest   => (421, "Misdirected Request"),
+        Status::InternalServerError  => (500, "Internal Server Error"),
+        Status::NotImplemented       => (501, "Not Implemented"),
+        Status::ServiceUnavailable   => (503, "Service Unavailable"),
     }
 }
 
-fn build_error_response(status: Status) -> Cow<'static, [u8]> {
-    let body = format!(HTML_ERROR!(), from_status(status.clone()).1,);
-    build_http_response(
-        status,
-        "text/html; charset=utf-8",
-        Cow::Owned(body.into_bytes()),
-    )
-}
-fn build_http_response(
-    status: Status,
-    content_type: &str,
-    initial_body: Cow<'static, [u8]>,
-) -> Cow<'static, [u8]> {
-    let (code, status_str) = from_status(status.clone());
-    let full_status_line = format!("HTTP/1.1 {} {}", code, status_str);
-
-    let mut headers = String::new();
-    let mut final_body = initial_body;
-
-    if let Status::MovedPermamently(url) = status {
-        if final_body.is_empty() {
-            let html = format!(HTML_MOVED!(), status_str, status_str, &url);
-            final_body = Cow::Owned(html.into_bytes());
+impl Status {
+    /// The redirect to `url` with the given status code, if it is a redirect code.
+    fn redirect(code: u16, url: String) -> Option<Status> {
+        match code {
+            301 => Some(Status::MovedPermanently(url)),
+            302 => Some(Status::Found(url)),
+            307 => Some(Status::TemporaryRedirect(url)),
+            308 => Some(Status::PermanentRedirect(url)),
+            _ => None,
         }
-        headers.push_str(&format!("Location: {}\r\n", url));
     }
 
-    headers.push_str(&format!("Content-Type: {}\r\n", content_type));
-    headers.push_str(&format!("Content-Length: {}\r\n", final_body.len()));
-
-    let mut response_bytes = vec![];
-    response_bytes.extend_from_slice(full_status_line.as_bytes());
-    response_bytes.extend_from_slice(b"\r\n");
-    response_bytes.extend_from_slice(headers.as_bytes());
-    response_bytes.extend_from_slice(b"\r\n");
-    response_bytes.extend_from_slice(&final_body);
+    fn location(&self) -> Option<&str> {
+        match self {
+            Status::MovedPermanently(url)
+            | Status::Found(url)
+            | Status::TemporaryRedirect(url)
+            | Status::PermanentRedirect(url) => Some(url),
+            _ => None,
+        }
+    }
+}
 
-    Cow::Owned(response_bytes)
+// RFC 9110 token characters
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
 }
 
-fn e_to_cow(p: &Path, e: std::io::Error) -> Cow<'static, [u8]> {
-    eprintln!("Error reading file {}: {}", p.display(), e);
-    build_error_response(Status::InternalServerError)
+fn is_valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(is_token_char)
+        && !value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0'))
 }
 
-fn build_response_other(ext: &str, p: &Path) -> Cow<'static, [u8]> {
-    // Attempt to guess the Content-Type based on the extension
-    let content_type = match ext.to_lowercase().as_str() {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "svg" => "image/svg+xml",
-        "pdf" => "application/pdf",
-        "json" => "application/json",
-        "xml" => "application/xml",
-        "css" => "text/css",
-        "js" => "application/javascript",
-        "txt" => "text/plain; charset=utf-8",
-        "bin" => "application/octet-stream",
-        _ => "application/octet-stream",
-    };
+// Serialized first, in this order. Every other field follows sorted by name, so the
+// bytes on the wire do not depend on the order handlers happen to add headers in.
+const CORE_HEADERS: [&str; 7] = [
+    "Date",
+    "Server",
+    "Content-Type",
+    "Content-Encoding",
+    "Content-Length",
+    "Transfer-Encoding",
+    "Cache-Control",
+];
 
-    match fs::read(p) {
-        Ok(file_bytes) => {
-            build_http_response(Status::Success, content_type, Cow::Owned(file_bytes))
-        }
-        Err(e) => e_to_cow(p, e),
-    }
+// the registered spellings title case would get wrong
+const HEADER_SPELLINGS: [&str; 3] = ["DAV", "ETag", "WWW-Authenticate"];
+
+fn header_rank(name: &str) -> (usize, String) {
+    let core = CORE_HEADERS
+        .iter()
+        .position(|core| core.eq_ignore_ascii_case(name))
+        .unwrap_or(CORE_HEADERS.len());
+    (core, name.to_ascii_lowercase())
 }
 
-fn is_path_safe(base_dir: &Path, requested_resource: &str) -> bool {
-    let canonical_base_dir = match base_dir.canonicalize() {
-        Ok(path) => path,
-        Err(e) => {
-            eprintln!(
-                "is_path_safe: Error canonicalizing base directory '{}': {}",
-                base_dir.display(),
-                e
-            );
-            return false;
-        }
-    };
-    let mut actual_target_path = canonical_base_dir.clone();
-    for component in PathBuf::from(requested_resource.trim_start_matches('/')).components() {
-        match component {
-            std::path::Component::Normal(name) => {
-                actual_target_path.push(name);
-            }
-            std::path::Component::CurDir => {}
-            std::path::Component::ParentDir => {
-                if !actual_target_path.pop() {
-                    return false;
+/// `content-type` and `CONTENT-TYPE` both go out as `Content-Type`.
+fn canonical_name(name: &str) -> String {
+    if let Some(spelling) = HEADER_SPELLINGS
+        .iter()
+        .find(|spelling| spelling.eq_ignore_ascii_case(name))
+    {
+        return spelling.to_string();
+    }
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
                 }
+                None => String::new(),
             }
-            std::path::Component::RootDir | std::path::Component::Prefix(_) => {
-                return false;
-            }
-        }
-    }
-    actual_target_path.starts_with(&canonical_base_dir)
+        })
+        .collect::<Vec<_>>()
+        .join("-")
 }
 
-fn handle_request(mut p: PathBuf, resource: &str, url: String) -> Cow<'static, [u8]> {
-    let resource_stripped = resource.trim_start_matches("/");
-    if !is_path_safe(&p, resource_stripped) {
-        eprintln!("Illegal path detected: {}", resource);
-        return build_error_response(Status::Forbidden);
+/// The header section of a response. Fields may be pushed in any order and with
+/// any casing, they are written out in canonical order with canonical names.
+struct HeaderBlock {
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderBlock {
+    fn new() -> Self {
+        HeaderBlock { fields: vec![] }
     }
-    p.push(resource_stripped);
-    if p.is_dir() {
-        let mut resource_formatted = resource.to_string();
-        if !resource_formatted.ends_with('/') {
-            resource_formatted.push('/');
-        }
-        let redirect_url = format!("{}{}index.html", url, resource_formatted);
-        #[cfg(debug_assertions)]
-        println!("Redirecting to: {}", redirect_url);
-        return build_http_response(
-            Status::MovedPermamently(redirect_url),
-            "text/html; charset=utf-8",
-            Cow::Owned(vec![]),
-        );
+
+    fn push(&mut self, name: &str, value: String) {
+        self.fields.push((canonical_name(name), value));
     }
-    match p.extension().and_then(|ext| ext.to_str()) {
-        Some("html") => match fs::read_to_string(&p) {
-            Ok(file_content) => build_http_response(
-                Status::Success,
-                "text/html; charset=utf-8",
-                Cow::Owned(file_content.into_bytes()),
-            ),
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                build_error_response(Status::PageNotFound)
-            }
-            Err(e) => {
-                eprintln!("Read to string failed with error: {}", e);
-                e_to_cow(&p, e)
-            }
-        },
-        Some(ext) => build_response_other(ext, &p),
-        _ => {
-            eprintln!("Unhandled path or file extension: {}", p.display());
-            build_error_response(Status::PageNotFound)
+
+    fn contains(&self, name: &str) -> bool {
+        self.fields
+            .iter()
+            .any(|(field, _)| field.eq_ignore_ascii_case(name))
+    }
+
+    /// The status line and the fields, up to and including the empty line.
+    fn into_head(mut self, status_line: &str) -> Vec<u8> {
+        // stable, so repeated fields keep the order they were added in
+        self.fields
+            .sort_by_cached_key(|(name, _)| header_rank(name));
+        let mut head = format!("{}\r\n", status_line);
+        for (name, value) in self.fields {
+            head.push_str(&format!("{}: {}\r\n", name, value));
         }
+        head.push_str("\r\n");
+        head.into_bytes()
     }
 }
 
-fn parse_host_address(host_str: &str) -> Option<&str> {
-    host_str
-        .strip_prefix("Host: ")
-        .and_then(|x| x.strip_prefix("http://").or(Some(x)))
-        .and_then(|x| x.split('/').next())
-        .map(|x| x.split_once(':').map_or(x, |(name, _port)| name))
-}
+type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
 
-const KEEP_ALIVE_TIMEOUT_MS: u64 = 1000;
-const MAX_REQUESTS_PER_CONNECTION: u32 = 100;
+enum Body {
+    // no message body at all, as opposed to a zero-length one (Content-Length: 0)
+    None,
+    Full(Cow<'static, [u8]>),
+    // read from disk while it is written out, its length is known up front
+    Sized(u64, BodyWriter),
+    // generated while it is written out, sent with chunked transfer coding
+    Stream(BodyWriter),
+}
 
-enum ReadRequestInitialError {
-    Io(io::Error),
-    Timeout,
-    ClientClosed,
-    EmptyRequest,
-    HeaderReadIo(io::Error),
+/// Frames everything written through it as HTTP/1.1 chunks.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+    written: u64,
 }
 
-fn read_request_line_and_headers(
-    rdr: &mut BufReader<&mut TcpStream>,
-) -> Result<(String, Vec<String>), ReadRequestInitialError> {
-    let mut request_line_str = String::new();
-    match rdr.read_line(&mut request_line_str) {
-        Ok(0) => return Err(ReadRequestInitialError::ClientClosed),
-        Ok(_) => {
-            if request_line_str.trim().is_empty() {
-                return Err(ReadRequestInitialError::EmptyRequest);
-            }
-        }
-        Err(e) => {
-            return if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
-                Err(ReadRequestInitialError::Timeout)
-            } else {
-                Err(ReadRequestInitialError::Io(e))
-            };
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would terminate the body early
+        if !buf.is_empty() {
+            write!(self.inner, "{:x}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+            self.written += buf.len() as u64;
         }
+        Ok(buf.len())
     }
 
-    let mut actual_headers = Vec::new();
-    loop {
-        let mut header_line = String::new();
-        match rdr.read_line(&mut header_line) {
-            Ok(0) => break,
-            Ok(_) => {
-                let trimmed = header_line.trim();
-                if trimmed.is_empty() {
-                    break;
-                }
-                actual_headers.push(trimmed.to_string());
-            }
-            Err(e) => return Err(ReadRequestInitialError::HeaderReadIo(e)),
-        }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
-    Ok((request_line_str, actual_headers))
 }
 
-fn determine_http_response(
-    request_line_str: &str,
-    actual_headers: &[String],
-    resource_dir: &Path,
-    server_listening_addr: SocketAddrV4,
-) -> Cow<'static, [u8]> {
-    match request_line_str
-        .trim()
-        .split(' ')
-        .collect::<Vec<_>>()
-        .as_slice()
-    {
-        ["GET", resource, "HTTP/1.1"] => {
-            let domain_name_option = actual_headers
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+// Sized bodies from this length on get their head flushed on its own, so the
+// client sees the status before the first block of a large file is read. Smaller
+// ones go out with the head in a single write.
+const FLUSH_HEAD_FROM: u64 = 64 * 1024;
+
+struct Response {
+    status: Status,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn with_header(mut self, name: &str, value: String) -> Self {
+        self.headers.push((name.to_string(), value));
+        self
+    }
+
+    /// Records a request header the response depends on. Every dimension ends up
+    /// in one Vary field when the response is written.
+    fn with_vary(self, dimension: &str) -> Self {
+        self.with_header("Vary", dimension.to_string())
+    }
+
+    fn closes_connection(&self) -> bool {
+        self.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("Connection") && value.eq_ignore_ascii_case("close")
+        })
+    }
+
+    fn code(&self) -> u16 {
+        from_status(self.status.clone()).0
+    }
+
+    /// The response to a HEAD request: the same headers, including the length a
+    /// GET would have announced, but no body. Sized bodies are never read.
+    fn into_head(mut self) -> Self {
+        let len = match &self.body {
+            Body::Full(body) => Some(body.len() as u64),
+            Body::Sized(len, _) => Some(*len),
+            Body::None | Body::Stream(_) => None,
+        };
+        if let Some(len) = len.filter(|_| !self.is_bodyless()) {
+            self.headers
+                .push(("Content-Length".to_string(), len.to_string()));
+        }
+        self.body = Body::None;
+        self
+    }
+
+    /// The response to OPTIONS on a resource, derived from what a GET would get:
+    /// the methods and codings on offer plus the current validators, but no
+    /// representation. Anything but a successful GET (404, redirects) passes through.
+    fn into_options(self, allow: String) -> Self {
+        if !matches!(self.status, Status::Success | Status::NotModified) {
+            return self;
+        }
+        let existing = |name: &str| {
+            self.headers
                 .iter()
-                .find_map(|h_str| parse_host_address(h_str.as_str()));
-
-            match domain_name_option {
-                Some(domain_name) => {
-                    let mut p = PathBuf::new();
-                    p.push(resource_dir);
-                    if env::var("HOST_NOT_DEFINED").unwrap_or_default() != "1" {
-                        p.push(domain_name);
-                    }
-                    let url_base =
-                        format!("http://{}:{}", domain_name, server_listening_addr.port());
-                    handle_request(p, resource, url_base)
-                }
-                None => {
-                    eprintln!("Host header not found or unparseable.");
-                    build_error_response(Status::BadRequest)
-                }
+                .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+        // only files are served in byte ranges, say so rather than leave clients guessing
+        let accept_ranges = existing("Accept-Ranges").unwrap_or("none".to_string());
+        let mut headers = vec![
+            ("Allow".to_string(), allow),
+            ("Accept-Ranges".to_string(), accept_ranges),
+        ];
+        // a Vary on Accept-Encoding means a precompressed copy exists
+        if existing("Vary").is_some_and(|vary| vary.contains("Accept-Encoding")) {
+            let codings: Vec<&str> = encoding::PRECOMPRESSED
+                .into_iter()
+                .map(encoding::Encoding::as_str)
+                .collect();
+            headers.push(("Accept-Encoding".to_string(), codings.join(", ")));
+        }
+        for validator in ["ETag", "Last-Modified"] {
+            if let Some(value) = existing(validator) {
+                headers.push((validator.to_string(), value));
             }
         }
-        _ => {
-            eprintln!(
-                "Unsupported or malformed request: {}",
-                request_line_str.trim()
-            );
-            build_error_response(Status::NotImplemented)
+        Response {
+            status: Status::NoContent,
+            headers,
+            body: Body::None,
         }
     }
-}
 
-fn write_response_to_stream(
-    stream: &mut TcpStream,
-    response_cow: &Cow<'static, [u8]>,
-) -> Result<(), io::Error> {
-    stream.write_all(response_cow)?;
-    stream.flush()?;
-    Ok(())
-}
+    /// A 103 interim response repeating the preload Link headers, so the client
+    /// can start fetching before the final response. None for anything but a 200.
+    fn early_hints(&self) -> Option<Vec<u8>> {
+        if !matches!(self.status, Status::Success) {
+            return None;
+        }
+        let mut hints = String::new();
+        for (_, value) in self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Link"))
+        {
+            hints.push_str(&format!("Link: {}\r\n", value));
+        }
+        if hints.is_empty() {
+            return None;
+        }
+        Some(format!("HTTP/1.1 103 Early Hints\r\n{}\r\n", hints).into_bytes())
+    }
 
-fn handle_connection(resource_dir: &Path, mut stream: TcpStream, addr: SocketAddrV4) {
-    let mut requests_served = 0;
-    let timeout_duration = Some(Duration::from_millis(KEEP_ALIVE_TIMEOUT_MS));
+    // these statuses never carry a body, a Content-Length would only mislead framing
+    fn is_bodyless(&self) -> bool {
+        matches!(self.code(), 101 | 204 | 304)
+    }
 
-    loop {
-        if requests_served >= MAX_REQUESTS_PER_CONNECTION {
-            #[cfg(debug_assertions)]
-            println!("Max requests per connection reached. Closing.");
-            break;
+    /// Serializes the response into `w` and returns the number of body bytes
+    /// sent, as reported in the access log.
+    fn write_to(self, w: &mut dyn Write) -> io::Result<u64> {
+        // Header values can carry request-derived text (redirect targets, echoed ids),
+        // a response that would split is replaced wholesale rather than patched up.
+        if let Some((name, value)) = self
+            .headers
+            .iter()
+            .find(|(name, value)| !is_valid_header(name, value))
+        {
+            log_error!("Refusing to send invalid header {:?}: {:?}", name, value);
+            return build_error_response(Status::InternalServerError, Framing::Safe).write_to(w);
         }
+        let bodyless = self.is_bodyless();
+        let (code, status_str) = from_status(self.status);
+        let full_status_line = format!("HTTP/1.1 {} {}", code, status_str);
 
-        if let Err(e) = stream.set_read_timeout(timeout_duration) {
-            eprintln!("Failed to set read timeout: {}. Closing connection.", e);
-            break;
+        let mut fields = HeaderBlock::new();
+        fields.push("Date", date::http_date(SystemTime::now()));
+        // A body frames itself below. Only a response without one (HEAD) may carry
+        // a Content-Length of its own, unless its status never has a body, and
+        // none may claim chunked coding.
+        let framed = !bodyless && !matches!(self.body, Body::None);
+        // repeated Vary fields are merged, not every cache combines them
+        let mut vary: Vec<&str> = vec![];
+        for (name, value) in &self.headers {
+            let overrides_framing = name.eq_ignore_ascii_case("Transfer-Encoding")
+                || ((framed || bodyless) && name.eq_ignore_ascii_case("Content-Length"));
+            if name.eq_ignore_ascii_case("Vary") {
+                for dimension in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+                    if !vary.iter().any(|seen| seen.eq_ignore_ascii_case(dimension)) {
+                        vary.push(dimension);
+                    }
+                }
+            } else if !overrides_framing {
+                fields.push(name, value.clone());
+            }
+        }
+        match vary.as_slice() {
+            [] => {}
+            // varying on everything, the other dimensions add nothing
+            _ if vary.contains(&"*") => fields.push("Vary", "*".to_string()),
+            _ => fields.push("Vary", vary.join(", ")),
         }
+        match &self.body {
+            _ if bodyless => {}
+            Body::None => {}
+            Body::Full(body) => fields.push("Content-Length", body.len().to_string()),
+            Body::Sized(len, _) => fields.push("Content-Length", len.to_string()),
+            Body::Stream(_) => fields.push("Transfer-Encoding", "chunked".to_string()),
+        }
+        debug_assert!(
+            !(fields.contains("Content-Length") && fields.contains("Transfer-Encoding")),
+            "response framed twice"
+        );
+        let mut head = fields.into_head(&full_status_line);
 
-        let mut rdr = BufReader::new(&mut stream);
-        let (request_line_str, actual_headers) = match read_request_line_and_headers(&mut rdr) {
-            Ok(parts) => parts,
-            Err(ReadRequestInitialError::ClientClosed) => {
-                #[cfg(debug_assertions)]
-                println!("Client closed connection (EOF).");
-                break;
+        match self.body {
+            _ if bodyless => {
+                w.write_all(&head)?;
+                Ok(0)
             }
-            Err(ReadRequestInitialError::EmptyRequest) => {
-                #[cfg(debug_assertions)]
-                println!("Received empty request line. Closing.");
-                break;
+            Body::None => {
+                w.write_all(&head)?;
+                Ok(0)
             }
-            Err(ReadRequestInitialError::Timeout) => {
-                #[cfg(debug_assertions)]
-                println!("Connection timed out due to inactivity.");
-                break;
+            Body::Full(body) => {
+                head.extend_from_slice(&body);
+                w.write_all(&head)?;
+                Ok(body.len() as u64)
             }
-            Err(ReadRequestInitialError::Io(e)) => {
-                eprintln!("Failed to read request line: {}. Closing connection.", e);
-                break;
+            Body::Sized(len, generate) => {
+                w.write_all(&head)?;
+                if len >= FLUSH_HEAD_FROM {
+                    w.flush()?;
+                }
+                generate(w)?;
+                Ok(len)
             }
-            Err(ReadRequestInitialError::HeaderReadIo(e)) => {
-                eprintln!("Error reading headers: {}. Closing connection.", e);
-                stream.shutdown(std::net::Shutdown::Both).ok();
-                return;
+            Body::Stream(generate) => {
+                w.write_all(&head)?;
+                let chunked = ChunkedWriter {
+                    inner: &mut *w,
+                    written: 0,
+                };
+                // coalesce the generator's small writes into reasonably sized chunks
+                let mut buffered = io::BufWriter::with_capacity(STREAM_CHUNK_SIZE, chunked);
+                generate(&mut buffered)?;
+                let chunked = buffered.into_inner().map_err(|e| e.into_error())?;
+                chunked.inner.write_all(b"0\r\n\r\n")?;
+                Ok(chunked.written)
             }
-        };
-
-        #[cfg(debug_assertions)]
-        {
-            println!("--- New Request ---");
-            println!("Request Line: {}", request_line_str.trim());
-            println!("Headers: {:#?}", actual_headers);
         }
+    }
+}
 
-        let wants_close = actual_headers
-            .iter()
-            .any(|header| header.eq_ignore_ascii_case("Connection: close"));
-        let response_cow =
-            determine_http_response(&request_line_str, &actual_headers, resource_dir, addr);
+/// Whether the server still knows where the next request on the connection
+/// starts after answering with an error.
+#[derive(Clone, Copy, PartialEq)]
+enum Framing {
+    // the request head was parsed completely and its body drained
+    Safe,
+    // unparseable or oversized input, whatever follows cannot be trusted
+    Unsafe,
+}
 
-        if let Err(e) = write_response_to_stream(&mut stream, &response_cow) {
-            eprintln!(
-                "Failed to write response to stream: {}. Closing connection.",
-                e
-            );
-            break;
-        }
+fn build_error_response(status: Status, framing: Framing) -> Response {
+    let title = from_status(status.clone()).1;
+    let body = match &status {
+        Status::NotAcceptable(available) => format!(HTML_NOT_ACCEPTABLE!(), title, available),
+        _ => format!(HTML_ERROR!(), title),
+    };
+    // error pages describe a transient condition, they must never be revalidated
+    let response = build_http_response(
+        status,
+        "text/html; charset=utf-8",
+        Cow::Owned(body.into_bytes()),
+    )
+    .with_header("Cache-Control", "no-store".to_string());
+    match framing {
+        Framing::Safe => response,
+        Framing::Unsafe => response.with_header("Connection", "close".to_string()),
+    }
+}
 
-        requests_served += 1;
+// `--verbose-errors`, set in main. Server faults mostly surface in filesystem
+// errors, far below anything holding the arguments.
+static VERBOSE_ERRORS: AtomicBool = AtomicBool::new(false);
 
-        if wants_close {
-            #[cfg(debug_assertions)]
-            println!("Client requested Connection: close.");
-            break;
+/// The 500 for a fault of the server, `detail` being logged. With
+/// `--verbose-errors` the page shows it as well, paths included, which is only
+/// meant for local debugging.
+fn server_error(detail: String) -> Response {
+    log_error!("{}", detail);
+    if !VERBOSE_ERRORS.load(Ordering::Relaxed) {
+        return build_error_response(Status::InternalServerError, Framing::Safe);
+    }
+    let title = from_status(Status::InternalServerError).1;
+    let body = format!(HTML_ERROR_DETAIL!(), title, html_escape(&detail));
+    build_http_response(
+        Status::InternalServerError,
+        "text/html; charset=utf-8",
+        Cow::Owned(body.into_bytes()),
+    )
+    .with_header("Cache-Control", "no-store".to_string())
+}
+
+#[derive(Clone, Copy)]
+enum ErrorFormat {
+    Html,
+    Json,
+    // JSON for clients that prefer it over HTML, going by Accept
+    Auto,
+}
+
+/// An error response with a JSON body in place of the HTML page. Its other
+/// headers are kept, successful responses pass through.
+fn json_error(mut response: Response) -> Response {
+    let (code, message) = from_status(response.status.clone());
+    if code < 400 {
+        return response;
+    }
+    let body = format!(
+        "{{\"error\":{{\"code\":{},\"message\":\"{}\"}}}}",
+        code, message
+    );
+    for (name, value) in &mut response.headers {
+        if name.eq_ignore_ascii_case("Content-Type") {
+            *value = "application/json".to_string();
         }
+    }
+    response.body = Body::Full(Cow::Owned(body.into_bytes()));
+    response
+}
 
-        #[cfg(debug_assertions)]
-        println!("Keeping connection alive for next request.");
+/// Whether an Accept value ranks application/json above text/html. Each is
+/// weighed by its most specific matching range, ties go to HTML.
+fn prefers_json(accept: Option<&str>) -> bool {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let ranges: Vec<(&str, u16)> = accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let range = params.next()?;
+            let mut weight = 1000;
+            for param in params {
+                if let Some((name, q)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        weight = encoding::parse_qvalue(q.trim())?;
+                    }
+                }
+            }
+            Some((range, weight))
+        })
+        .collect();
+    let weight_of = |media_type: &str| {
+        let (kind, _) = media_type.split_once('/').unwrap_or_default();
+        [
+            media_type.to_string(),
+            format!("{}/*", kind),
+            "*/*".to_string(),
+        ]
+        .iter()
+        .find_map(|candidate| {
+            ranges
+                .iter()
+                .find(|(range, _)| range.eq_ignore_ascii_case(candidate))
+                .map(|(_, weight)| *weight)
+        })
+        .unwrap_or(0)
+    };
+    weight_of("application/json") > weight_of("text/html")
+}
+
+fn build_http_response(status: Status, content_type: &str, body: Cow<'static, [u8]>) -> Response {
+    let mut headers = vec![];
+    if let Some(url) = status.location() {
+        headers.push(("Location".to_string(), url.to_string()));
     }
+    headers.push(("Content-Type".to_string(), content_type.to_string()));
 
-    #[cfg(debug_assertions)]
-    println!(
-        "Connection with {} closed after {} requests.",
-        addr, requests_served
-    );
+    Response {
+        status,
+        headers,
+        body: Body::Full(body),
+    }
+}
 
-    stream
-        .shutdown(std::net::Shutdown::Both)
-        .unwrap_or_else(|e| {
-            eprintln!("Failed to shutdown stream: {}", e);
-        });
+/// A redirect with the generated "document has moved" page as its body. `status`
+/// must be one of the redirect statuses, it carries the target.
+fn build_redirect_response(status: Status) -> Response {
+    let status_str = from_status(status.clone()).1;
+    // the target is built from the request path, it must not inject markup
+    let url = html_escape(status.location().unwrap_or_default());
+    let html = format!(HTML_MOVED!(), status_str, status_str, url);
+    build_http_response(
+        status,
+        "text/html; charset=utf-8",
+        Cow::Owned(html.into_bytes()),
+    )
 }
 
-struct ProgArgs {
-    port: u16,
-    directory: PathBuf,
+/// The `--dir-redirect-code` redirect used for directories and their index.
+fn directory_redirect(args: &ProgArgs, url: String) -> Response {
+    match Status::redirect(args.dir_redirect_code, url) {
+        Some(status) => build_redirect_response(status),
+        // parse_args only accepts redirect codes
+        None => build_error_response(Status::InternalServerError, Framing::Safe),
+    }
 }
-fn parse_args(mut args: Args) -> Option<ProgArgs> {
-    let _name = args.next()?;
-    let port = args.next()?.parse().ok()?;
-    let directory = args.next()?.parse().ok()?;
-    Some(ProgArgs { port, directory })
+
+fn build_streamed_response(status: Status, content_type: &str, generate: BodyWriter) -> Response {
+    Response {
+        status,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body: Body::Stream(generate),
+    }
 }
-fn main() {
-    let args = match parse_args(env::args()) {
-        Some(x) => x,
-        None => {
-            eprintln!("usage: http_server [port] [directory]");
-            std::process::exit(1);
+
+fn find_header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|h| {
+        let (key, value) = h.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Whether the client asked for the connection to close. Connection holds a list
+/// of options: an h2c upgrade attempt sends `Connection: Upgrade, HTTP2-Settings`,
+/// the upgrade being ignored (RFC 9110, section 7.8) and the request served over
+/// HTTP/1.1 on a connection that stays open.
+fn wants_close(headers: &[String]) -> bool {
+    headers
+        .iter()
+        .filter_map(|h| h.split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("close"))
+}
+
+// at most one of each may be sent, two could be read differently by a proxy
+const SINGLETON_HEADERS: [&str; 1] = ["Host"];
+
+/// Whether the header section must be refused with a 400: it uses obsolete line
+/// folding (RFC 9112, section 5.2) or repeats a header that must be unique.
+fn has_malformed_headers(headers: &[String]) -> bool {
+    if headers.iter().any(|h| h.starts_with([' ', '\t'])) {
+        log_error!("Rejecting request with a folded header line");
+        return true;
+    }
+    for name in SINGLETON_HEADERS {
+        let count = headers
+            .iter()
+            .filter(|h| {
+                h.split_once(':')
+                    .is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            })
+            .count();
+        if count > 1 {
+            log_error!("Rejecting request with {} {} headers", count, name);
+            return true;
         }
-    };
+    }
+    false
+}
+
+/// Weak validator derived from a size and modification time, shared by files
+/// (byte length) and directory listings (child count) so neither needs to be
+/// read or rendered to answer a conditional request.
+fn weak_etag(size: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!(
+        "W/\"{:x}-{:x}.{:x}\"",
+        size,
+        mtime.as_secs(),
+        mtime.subsec_nanos()
+    )
+}
+
+/// `etag` told apart by `encoding`, `W/"1f-2a.0"` becoming `W/"1f-2a.0-gzip"`: a
+/// cache holding the identity body must never have it confirmed by a 304 to a
+/// request that got the compressed one, or the other way around.
+fn encoded_etag(etag: String, encoding: encoding::Encoding) -> String {
+    match (encoding, etag.strip_suffix('"')) {
+        (encoding::Encoding::Identity, _) | (_, None) => etag,
+        (encoding, Some(opaque)) => format!("{}-{}\"", opaque, encoding.as_str()),
+    }
+}
+
+// If-None-Match always uses the weak comparison function (RFC 7232 3.2)
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|candidate| opaque(candidate) == opaque(etag))
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Answers with 304 when the client already holds `etag` (or, lacking an
+/// If-None-Match, a copy at least as new as `last_modified`), otherwise renders
+/// the full response lazily and attaches the validators to it.
+fn conditional_response<F>(
+    req_headers: &[String],
+    etag: String,
+    last_modified: Option<SystemTime>,
+    render: F,
+) -> Response
+where
+    F: FnOnce() -> Response,
+{
+    let not_modified = match (
+        find_header(req_headers, "If-None-Match"),
+        find_header(req_headers, "If-Modified-Since"),
+    ) {
+        (Some(if_none_match), _) => etag_matches(if_none_match, &etag),
+        // HTTP dates have one second resolution, mtimes usually do not. A date in
+        // the future cannot come from a Last-Modified we sent, it is ignored.
+        (None, Some(since)) => last_modified
+            .zip(date::parse_http_date(since).filter(|since| *since <= SystemTime::now()))
+            .is_some_and(|(modified, since)| unix_secs(modified) <= unix_secs(since)),
+        (None, None) => false,
+    };
+    let response = if not_modified {
+        Response {
+            status: Status::NotModified,
+            headers: vec![],
+            body: Body::None,
+        }
+    } else {
+        render()
+    };
+    match response.status {
+        Status::Success | Status::PartialContent | Status::NotModified => {
+            let response = response.with_header("ETag", etag);
+            match last_modified {
+                Some(modified) => response.with_header("Last-Modified", date::http_date(modified)),
+                None => response,
+            }
+        }
+        _ => response,
+    }
+}
+
+/// The single byte range a Range header value asks for out of `len` bytes, as
+/// (first, last) offsets. None when the header is to be ignored and the whole
+/// representation sent: another unit, several ranges, or a malformed one.
+/// Some(None) when not a byte of the range exists, which is answered 416.
+fn byte_range(value: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let (unit, set) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || set.contains(',') {
+        return None;
+    }
+    let (first, last) = set.trim().split_once('-')?;
+    let parse = |n: &str| {
+        n.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| n.parse().ok())?
+    };
+    let range = match (first, last) {
+        // the final `last` bytes
+        ("", suffix) => match parse(suffix)? {
+            0 => None,
+            suffix => Some((len.saturating_sub(suffix), len.saturating_sub(1))),
+        },
+        (first, "") => Some((parse(first)?, len.saturating_sub(1))),
+        (first, last) => {
+            let (first, last) = (parse(first)?, parse(last)?);
+            if last < first {
+                return None;
+            }
+            Some((first, last.min(len.saturating_sub(1))))
+        }
+    };
+    Some(range.filter(|(first, _)| *first < len))
+}
+
+/// Whether a Range may be honoured under the request's If-Range: without one,
+/// or when it names the current representation. Weak ETags never match.
+fn range_applies(req_headers: &[String], etag: &str, last_modified: Option<SystemTime>) -> bool {
+    match find_header(req_headers, "If-Range") {
+        None => true,
+        Some(if_range) if if_range.starts_with('"') => !etag.starts_with("W/") && if_range == etag,
+        Some(if_range) => {
+            last_modified.is_some_and(|modified| date::http_date(modified) == if_range)
+        }
+    }
+}
+
+// Filesystem errors are mostly the client's problem (missing or unreadable file,
+// including one removed between the directory check and the read), only the
+// remaining kinds are reported as a server fault.
+fn e_to_cow(p: &Path, e: std::io::Error) -> Response {
+    match e.kind() {
+        // a file name used as a directory (`/a.txt/x`) is just as missing
+        io::ErrorKind::NotFound
+        | io::ErrorKind::NotADirectory
+        | io::ErrorKind::PermissionDenied => {
+            #[cfg(debug_assertions)]
+            println!("Cannot open {}: {}", p.display(), e);
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                build_error_response(Status::Forbidden, Framing::Safe)
+            } else {
+                build_error_response(Status::PageNotFound, Framing::Safe)
+            }
+        }
+        _ => server_error(format!("Error reading file {}: {}", p.display(), e)),
+    }
+}
+
+/// How a trailing slash that does not match the resource is dealt with.
+#[derive(Clone, Copy)]
+enum TrailingSlash {
+    // directories get one, files lose it
+    Redirect,
+    // answered 404
+    Strict,
+    // served either way
+    Ignore,
+}
+
+#[derive(Clone, Copy)]
+enum CacheProfile {
+    None,
+    Static,
+    Aggressive,
+}
+
+const CACHE_FOREVER: &str = "public, max-age=31536000";
+const CACHE_FOREVER_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_SHORT: &str = "public, max-age=3600";
+
+// bundlers name assets like `app.3f9a2b1c.js`, such a file never changes content
+fn is_fingerprinted(p: &Path) -> bool {
+    p.file_stem()
+        .and_then(|stem| stem.to_str())
+        .is_some_and(|stem| {
+            stem.split(['.', '-'])
+                .skip(1)
+                .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+        })
+}
+
+/// Matches a file name against a pattern where `*` stands for any run of
+/// characters and `?` for exactly one.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain([name.len()])
+                .any(|i| glob_matches(rest, &name[i..]))
+        }
+        Some(c) => {
+            let mut chars = name.chars();
+            match chars.next() {
+                Some(n) if c == '?' || c == n => {
+                    glob_matches(&pattern[c.len_utf8()..], chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Files named by `--immutable-pattern` never change content under the same name.
+fn is_immutable(args: &ProgArgs, p: &Path) -> bool {
+    p.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            args.immutable_patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern, name))
+        })
+}
+
+/// Strong validator for an immutable file: its name is enough to tell versions
+/// apart, and the precompressed copy has a name of its own.
+fn name_etag(p: &Path) -> Option<String> {
+    p.file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.bytes().all(|b| b.is_ascii_graphic() && b != b'"'))
+        .map(|name| format!("\"{}\"", name))
+}
+
+fn cache_control(profile: CacheProfile, p: &Path) -> Option<&'static str> {
+    let ext = p
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match profile {
+        CacheProfile::None => None,
+        CacheProfile::Aggressive => Some(CACHE_FOREVER),
+        CacheProfile::Static => match ext.as_str() {
+            "html" => Some("no-cache"),
+            _ if is_fingerprinted(p) => Some(CACHE_FOREVER_IMMUTABLE),
+            _ => Some(CACHE_SHORT),
+        },
+    }
+}
+
+// Attempt to guess the Content-Type based on the extension
+// extensions are compared case-insensitively
+#[rustfmt::skip]
+const MEDIA_TYPES: [(&str, &str); 13] = [
+    ("html", "text/html"),
+    ("png",  "image/png"),
+    ("jpg",  "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif",  "image/gif"),
+    ("svg",  "image/svg+xml"),
+    ("pdf",  "application/pdf"),
+    ("json", "application/json"),
+    ("xml",  "application/xml"),
+    ("css",  "text/css"),
+    ("js",   "application/javascript"),
+    ("txt",  "text/plain"),
+    ("bin",  "application/octet-stream"),
+];
+const DEFAULT_MEDIA_TYPE: &str = "application/octet-stream";
+
+fn media_type(ext: &str) -> &'static str {
+    MEDIA_TYPES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+        .map_or(DEFAULT_MEDIA_TYPE, |(_, media_type)| media_type)
+}
+
+/// `--list-mime`: the content type every known extension is served with, then
+/// the fallback and the `--type` rules that take precedence over both.
+fn print_media_types(args: &ProgArgs) {
+    let mut table = String::new();
+    for (ext, _) in MEDIA_TYPES {
+        table.push_str(&format!(".{}\t{}\n", ext, content_type(args, ext)));
+    }
+    table.push_str(&format!("*\t{}\n", DEFAULT_MEDIA_TYPE));
+    for (path, content_type) in &args.type_overrides {
+        table.push_str(&format!("{}\t{}\n", path, content_type));
+    }
+    // piped into head or grep, a closed stdout is not worth a panic
+    let _ = io::stdout().write_all(table.as_bytes());
+}
+
+/// The Content-Type of a file with extension `ext`. Textual types carry the
+/// `--charset` configured for the extension, or the default one.
+fn content_type(args: &ProgArgs, ext: &str) -> String {
+    let media_type = media_type(ext);
+    if !media_type.starts_with("text/") {
+        return media_type.to_string();
+    }
+    let charset = args
+        .charset_overrides
+        .iter()
+        .find(|(overridden, _)| overridden.eq_ignore_ascii_case(ext))
+        .map_or(args.charset.as_str(), |(_, charset)| charset.as_str());
+    format!("{}; charset={}", media_type, charset)
+}
+
+// Windows resolves these to devices in any directory and with any extension.
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether a single request path component may reach the filesystem. The Windows
+/// rules are plain string checks that behave the same on every platform, they are
+/// only enforced when `windows` is set.
+fn component_allowed(name: &str, windows: bool) -> bool {
+    if !windows {
+        return true;
+    }
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ');
+    // a colon selects a drive or an alternate data stream (`file.txt::$DATA`), and
+    // trailing dots and spaces are silently dropped, so `a.html.` opens `a.html`
+    !name.contains(':')
+        && !name.ends_with(['.', ' '])
+        && !WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// Resolves a request path below `base_dir`, or None when it would leave it. Dot
+/// segments are resolved lexically, and the result is what gets opened, so the OS
+/// never gets to resolve `..` through a symlink. Symlinks are only followed when
+/// they point back inside the base, and not at all without `follow_symlinks`.
+fn resolve_path(
+    base_dir: &Path,
+    requested_resource: &str,
+    follow_symlinks: bool,
+) -> Option<PathBuf> {
+    // a base directory gone missing is told apart by the callers that care
+    let canonical_base_dir = base_dir.canonicalize().ok()?;
+    let mut actual_target_path = canonical_base_dir.clone();
+    for component in PathBuf::from(requested_resource.trim_start_matches('/')).components() {
+        match component {
+            std::path::Component::Normal(name) => {
+                if !name
+                    .to_str()
+                    .is_some_and(|name| component_allowed(name, cfg!(windows)))
+                {
+                    return None;
+                }
+                actual_target_path.push(name);
+                let is_symlink = fs::symlink_metadata(&actual_target_path)
+                    .is_ok_and(|meta| meta.file_type().is_symlink());
+                if is_symlink && !follow_symlinks {
+                    return None;
+                }
+            }
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !actual_target_path.pop() {
+                    return None;
+                }
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => {
+                return None;
+            }
+        }
+    }
+    if !actual_target_path.starts_with(&canonical_base_dir) {
+        return None;
+    }
+    match actual_target_path.canonicalize() {
+        Ok(real) if !real.starts_with(&canonical_base_dir) => None,
+        // a missing path is not a safety problem, it ends up as a 404
+        _ => Some(actual_target_path),
+    }
+}
+
+/// The path of a request target with its query, empty and `.` segments removed
+/// and `..` applied, as `--type` entries are compared. None when it climbs
+/// above the root.
+fn normalize_url_path(resource: &str) -> Option<String> {
+    let path = resource.split('?').next().unwrap_or_default();
+    let mut segments = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            _ => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn build_listing_response(p: &Path, resource: &str, max_entries: usize) -> Response {
+    let entries = match fs::read_dir(p) {
+        Ok(entries) => entries,
+        Err(e) => return e_to_cow(p, e),
+    };
+    // only the names up to the cap are ever held in memory
+    let mut names = vec![];
+    let mut omitted = 0usize;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if names.len() >= max_entries {
+            omitted += 1;
+            continue;
+        }
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let title = html_escape(resource);
+    let is_root = resource == "/";
+    // served without its trailing slash (--trailing-slash ignore), relative links
+    // would resolve against the parent
+    let base = match resource.ends_with('/') {
+        true => String::new(),
+        false => format!(
+            "{}/",
+            html_escape(resource.rsplit('/').next().unwrap_or_default())
+        ),
+    };
+    build_streamed_response(
+        Status::Success,
+        "text/html; charset=utf-8",
+        Box::new(move |w| {
+            write!(w, HTML_LISTING_HEAD!(), title)?;
+            if !is_root {
+                writeln!(w, "<li><a href=\"{}../\">../</a></li>", base)?;
+            }
+            for name in &names {
+                let name = html_escape(name);
+                writeln!(w, "<li><a href=\"{0}{1}\">{1}</a></li>", base, name)?;
+            }
+            if omitted > 0 {
+                write!(
+                    w,
+                    "</ul>\n<p>Listing truncated, {} more entries not shown.</p>\n<ul>\n",
+                    omitted
+                )?;
+            }
+            w.write_all(HTML_LISTING_TAIL.as_bytes())
+        }),
+    )
+}
+
+/// `has_index` tells whether an index.html exists for the directory, in any of
+/// the overlaid roots.
+fn handle_directory(
+    p: &Path,
+    resource: &str,
+    url: String,
+    req_headers: &[String],
+    has_index: bool,
+    args: &ProgArgs,
+) -> Response {
+    if !args.autoindex || has_index {
+        let mut resource_formatted = resource.to_string();
+        if !resource_formatted.ends_with('/') {
+            resource_formatted.push('/');
+        }
+        let redirect_url = format!("{}{}index.html", url, resource_formatted);
+        #[cfg(debug_assertions)]
+        println!("Redirecting to: {}", redirect_url);
+        return directory_redirect(args, redirect_url);
+    }
+    if !resource.ends_with('/') && matches!(args.trailing_slash, TrailingSlash::Redirect) {
+        // relative links in the listing only resolve against a trailing slash
+        return directory_redirect(args, format!("{}{}/", url, resource));
+    }
+
+    let (child_count, modified) = match (fs::read_dir(p), fs::metadata(p)) {
+        (Ok(entries), Ok(meta)) => (entries.count() as u64, meta.modified().ok()),
+        (Err(e), _) | (_, Err(e)) => return e_to_cow(p, e),
+    };
+    // entries being added or removed bump the directory mtime
+    conditional_response(
+        req_headers,
+        weak_etag(child_count, modified),
+        modified,
+        || build_listing_response(p, resource, args.autoindex_max_entries),
+    )
+}
+
+/// The file or directory `resource` names below `root`, or the error response
+/// when it is off limits, known not to exist, or unreachable through a loop of
+/// symlinks (`a -> b -> a`, which the OS gives up on after a bounded number of
+/// hops). Such a loop is a broken deployment, not a missing file.
+fn resolve_target(root: &Path, resource: &str, args: &ProgArgs) -> Result<PathBuf, Response> {
+    let p = match resolve_path(root, resource.trim_start_matches('/'), args.follow_symlinks) {
+        Some(p) => p,
+        None => {
+            // deleted or unmounted while serving, no fault of the client
+            if let Err(e) = root.canonicalize() {
+                let detail = format!("Document root {} is unavailable: {}", root.display(), e);
+                return Err(server_error(detail));
+            }
+            log_error!("Illegal path detected: {}", resource);
+            return Err(build_error_response(Status::Forbidden, Framing::Safe));
+        }
+    };
+    if args
+        .tree_index
+        .as_ref()
+        .is_some_and(|index| !index.may_exist(&p))
+    {
+        return Err(build_error_response(Status::PageNotFound, Framing::Safe));
+    }
+    // missing and unreadable paths are answered further on, anything else keeping
+    // the path from resolving is typically the loop (ELOOP has no stable ErrorKind)
+    match fs::metadata(&p) {
+        Err(e)
+            if !matches!(
+                e.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            return Err(server_error(format!(
+                "Cannot resolve {}: {}",
+                p.display(),
+                e
+            )));
+        }
+        _ => {}
+    }
+    Ok(p)
+}
+
+/// Resolves `resource` in the first of `roots` that has it, so each root
+/// overrides the ones after it. A path refused or broken in one root is an error
+/// outright, and one that none of them has is resolved in the first.
+fn resolve_overlay(roots: &[&Path], resource: &str, args: &ProgArgs) -> Result<PathBuf, Response> {
+    let mut missing = None;
+    for root in roots {
+        match resolve_target(root, resource, args) {
+            Ok(p) if p.exists() => return Ok(p),
+            // only a missing path falls through to the next root
+            Err(response) if !matches!(response.status, Status::PageNotFound) => {
+                return Err(response)
+            }
+            resolved => {
+                missing.get_or_insert(resolved);
+            }
+        }
+    }
+    missing.unwrap_or_else(|| Err(build_error_response(Status::PageNotFound, Framing::Safe)))
+}
+
+/// `--probe-extensions`: the first file found by appending one of them to a
+/// missing `resource` whose last segment has no extension.
+fn probe_extensions(roots: &[&Path], resource: &str, args: &ProgArgs) -> Option<PathBuf> {
+    let name = resource.rsplit('/').next()?;
+    if name.is_empty() || Path::new(name).extension().is_some() {
+        return None;
+    }
+    args.probe_extensions.iter().find_map(|ext| {
+        // resolved like any request path, so the same checks apply to it
+        resolve_overlay(roots, &format!("{}.{}", resource, ext), args)
+            .ok()
+            .filter(|p| p.is_file())
+    })
+}
+
+/// Serves `resource` from `roots`, the document root followed by any `--root`
+/// fallbacks, to a request with `method`.
+fn handle_request(
+    roots: &[&Path],
+    resource: &str,
+    url: String,
+    method: &str,
+    req_headers: &[String],
+    forced_type: Option<&str>,
+    args: &ProgArgs,
+) -> Response {
+    let resource_stripped = resource.trim_start_matches("/");
+    let has_index = |dir: &str| {
+        let index = format!("{}/index.html", dir.trim_end_matches('/'));
+        resolve_overlay(roots, &index, args).is_ok_and(|p| p.is_file())
+    };
+    // the site root is always a directory listing or its index, never a file lookup
+    if resource_stripped.is_empty() {
+        let (root, has_index) = (roots[0], has_index("/"));
+        return handle_directory(root, "/", url, req_headers, has_index, args);
+    }
+    let p = match resolve_overlay(roots, resource, args) {
+        Ok(p) if !p.exists() => probe_extensions(roots, resource, args).unwrap_or(p),
+        Ok(p) => p,
+        Err(response) => match response.status {
+            Status::PageNotFound => match probe_extensions(roots, resource, args) {
+                Some(p) => p,
+                None => return response,
+            },
+            _ => return response,
+        },
+    };
+    let slashed = resource.ends_with('/');
+    match (p.is_dir(), slashed, args.trailing_slash) {
+        (true, false, TrailingSlash::Strict) | (false, true, TrailingSlash::Strict) => {
+            build_error_response(Status::PageNotFound, Framing::Safe)
+        }
+        (true, _, _) => handle_directory(&p, resource, url, req_headers, has_index(resource), args),
+        (false, true, TrailingSlash::Redirect) => {
+            directory_redirect(args, format!("{}{}", url, resource.trim_end_matches('/')))
+        }
+        (false, _, _) => serve_file(&p, method, req_headers, forced_type, args),
+    }
+}
+
+// `app.js` is looked up as `app.js.br` or `app.js.gz`, next to the original
+fn compressed_sibling(p: &Path, encoding: encoding::Encoding) -> Option<PathBuf> {
+    let mut name = p.file_name()?.to_os_string();
+    name.push(".");
+    name.push(encoding.extension()?);
+    let sibling = p.with_file_name(name);
+    sibling.is_file().then_some(sibling)
+}
+
+const UTF8_BOM: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
+// leaves `file` positioned after a leading byte order mark, returns its length
+fn skip_bom(file: &mut fs::File) -> io::Result<u64> {
+    let mut head = [0; 3];
+    match file.read_exact(&mut head) {
+        Ok(()) if head == UTF8_BOM => Ok(3),
+        // shorter than a BOM, or starting with something else
+        Ok(()) | Err(_) => file.seek(SeekFrom::Start(0)).map(|_| 0),
+    }
+}
+
+/// Opens `p` for a conditional response. The file is only opened here, its bytes
+/// are copied out while the response is written, so HEAD never reads it. With
+/// `strip_bom` a leading UTF-8 byte order mark is left out of the body, with
+/// `mmap` the body is sent from a memory mapping. An `immutable` file is
+/// validated by its name alone, without a modification time. A file holding a
+/// compressed copy is sent with its `encoding`, under an ETag of its own. A
+/// Range on the identity body is answered 206 with just those bytes, the file
+/// being read from the start of the range, never from its own start.
+#[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+fn serve_opened(
+    p: &Path,
+    req_headers: &[String],
+    content_type: &str,
+    strip_bom: bool,
+    mmap: bool,
+    immutable: bool,
+    encoding: encoding::Encoding,
+) -> Response {
+    let opened = fs::File::open(p).and_then(|mut f| {
+        let meta = f.metadata()?;
+        let skipped = if strip_bom { skip_bom(&mut f)? } else { 0 };
+        Ok((f, meta, skipped))
+    });
+    let (mut file, meta, skipped) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return e_to_cow(p, e),
+    };
+    // u64 from the metadata to the Content-Length, files past 4 GiB fit on 32-bit targets
+    let len = meta.len() - skipped;
+    let (etag, modified) = match name_etag(p).filter(|_| immutable) {
+        Some(etag) => (etag, None),
+        None => {
+            let modified = meta.modified().ok();
+            (weak_etag(len, modified), modified)
+        }
+    };
+    let mut headers = vec![("Content-Type".to_string(), content_type.to_string())];
+    let range = match encoding {
+        encoding::Encoding::Identity => {
+            headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+            find_header(req_headers, "Range")
+                .filter(|_| range_applies(req_headers, &etag, modified))
+                .and_then(|range| byte_range(range, len))
+        }
+        encoding => {
+            headers.push((
+                "Content-Encoding".to_string(),
+                encoding.as_str().to_string(),
+            ));
+            None
+        }
+    };
+    let etag = encoded_etag(etag, encoding);
+    let (status, start, len) = match range {
+        None => (Status::Success, 0, len),
+        Some(Some((first, last))) => {
+            let content_range = format!("bytes {}-{}/{}", first, last, len);
+            headers.push(("Content-Range".to_string(), content_range));
+            (Status::PartialContent, first, last - first + 1)
+        }
+        // decided after the conditional check, a client up to date gets its 304
+        Some(None) => (Status::RangeNotSatisfiable, 0, len),
+    };
+    conditional_response(req_headers, etag, modified, || match status {
+        Status::RangeNotSatisfiable => {
+            build_error_response(Status::RangeNotSatisfiable, Framing::Safe)
+                .with_header("Content-Range", format!("bytes */{}", len))
+        }
+        status => Response {
+            status,
+            headers,
+            body: Body::Sized(
+                len,
+                Box::new(move |w| {
+                    // an empty file cannot be mapped, there is nothing to send anyway
+                    #[cfg(feature = "mmap")]
+                    if mmap && len > 0 {
+                        return mapped::write(&file, skipped + start, len, w);
+                    }
+                    if start > 0 {
+                        file.seek(SeekFrom::Start(skipped + start))?;
+                    }
+                    let copied = io::copy(&mut file.take(len), w)?;
+                    if copied < len {
+                        // the announced length can no longer be honoured, the connection must drop
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "file shrank while being sent",
+                        ));
+                    }
+                    Ok(())
+                }),
+            ),
+        },
+    })
+}
+
+/// Serves the HTML page at `p` with the `--inject-*` snippets spliced in. The
+/// page has to be read whole to find the tags, only after the conditional check.
+fn serve_injected(
+    p: &Path,
+    req_headers: &[String],
+    content_type: &str,
+    strip_bom: bool,
+    inject: &inject::Inject,
+) -> Response {
+    let meta = match fs::metadata(p) {
+        Ok(meta) => meta,
+        Err(e) => return e_to_cow(p, e),
+    };
+    let modified = meta.modified().ok();
+    // the snippets are fixed for the run, the page alone decides its length
+    let etag = weak_etag(meta.len() + inject.len(), modified);
+    conditional_response(req_headers, etag, modified, || {
+        let mut page = match fs::read(p) {
+            Ok(page) => page,
+            Err(e) => return e_to_cow(p, e),
+        };
+        if strip_bom && page.starts_with(&UTF8_BOM) {
+            page.drain(..UTF8_BOM.len());
+        }
+        build_http_response(
+            Status::Success,
+            content_type,
+            Cow::Owned(inject.apply(page)),
+        )
+    })
+}
+
+// `photo.jpg` has its lighter variant in `photo.sd.jpg`, next to it
+fn light_variant(p: &Path) -> Option<PathBuf> {
+    let (stem, ext) = (p.file_stem()?, p.extension()?);
+    let mut name = stem.to_os_string();
+    name.push(".sd.");
+    name.push(ext);
+    let light = p.with_file_name(name);
+    light.is_file().then_some(light)
+}
+
+/// Serves the file at `p` with `forced_type` as its content type if given,
+/// otherwise with the one guessed from its extension. A file without one, such
+/// as `LICENSE`, is sent as application/octet-stream. Only a GET is answered in
+/// byte ranges, a HEAD describes the whole file as its GET would without a Range.
+fn serve_file(
+    p: &Path,
+    method: &str,
+    req_headers: &[String],
+    forced_type: Option<&str>,
+    args: &ProgArgs,
+) -> Response {
+    if let Err(e) = fs::metadata(p) {
+        return e_to_cow(p, e);
+    }
+    let unranged: Vec<String>;
+    let req_headers = match method {
+        "GET" => req_headers,
+        _ => {
+            unranged = req_headers
+                .iter()
+                .filter(|h| find_header(std::slice::from_ref(h), "Range").is_none())
+                .cloned()
+                .collect();
+            &unranged
+        }
+    };
+    let light = args.save_data.then(|| light_variant(p)).flatten();
+    let save_data =
+        find_header(req_headers, "Save-Data").is_some_and(|value| value.eq_ignore_ascii_case("on"));
+    // the variant stands in for the file, its type and caching included
+    let p = match &light {
+        Some(light) if save_data => light.as_path(),
+        _ => p,
+    };
+    // missing and unreadable files were answered above, whatever their name
+    let content_type = match (forced_type, p.extension().and_then(|ext| ext.to_str())) {
+        (Some(forced), _) => forced.to_string(),
+        (None, Some(ext)) => content_type(args, ext),
+        (None, None) => DEFAULT_MEDIA_TYPE.to_string(),
+    };
+    let injected = args.inject.is_active() && content_type.starts_with("text/html");
+    // the compressed copies cannot take the snippets
+    let siblings: Vec<_> = match injected {
+        true => vec![],
+        false => encoding::PRECOMPRESSED
+            .into_iter()
+            .filter_map(|encoding| Some((encoding, compressed_sibling(p, encoding)?)))
+            .collect(),
+    };
+    let immutable = is_immutable(args, p);
+    // Byte ranges are offsets into the identity representation, so a request that
+    // carries one never gets a precompressed copy.
+    let ranged = find_header(req_headers, "Range").is_some();
+    let mut available: Vec<_> = match ranged {
+        true => vec![],
+        false => siblings.iter().map(|(encoding, _)| *encoding).collect(),
+    };
+    available.push(encoding::Encoding::Identity);
+    let negotiated =
+        encoding::negotiate_encoding(find_header(req_headers, "Accept-Encoding"), &available);
+    let sibling = siblings
+        .iter()
+        .find(|(encoding, _)| Some(*encoding) == negotiated);
+    let response = match (sibling, negotiated) {
+        (_, None) => {
+            let available: Vec<_> = available.iter().map(|coding| coding.as_str()).collect();
+            build_error_response(Status::NotAcceptable(available.join(", ")), Framing::Safe)
+        }
+        // the BOM of a compressed copy cannot be reached without decompressing it
+        (Some((encoding, sibling)), _) => serve_opened(
+            sibling,
+            req_headers,
+            &content_type,
+            false,
+            args.mmap,
+            immutable,
+            *encoding,
+        ),
+        (None, _) => {
+            let strip_bom = args.strip_bom && content_type.starts_with("text/");
+            match injected {
+                true => serve_injected(p, req_headers, &content_type, strip_bom, &args.inject),
+                false => serve_opened(
+                    p,
+                    req_headers,
+                    &content_type,
+                    strip_bom,
+                    args.mmap,
+                    immutable,
+                    encoding::Encoding::Identity,
+                ),
+            }
+        }
+    };
+    // caches must not hand a compressed copy to a client that cannot decode it
+    let response = match siblings.is_empty() {
+        false => response.with_vary("Accept-Encoding"),
+        true => response,
+    };
+    let response = match light {
+        Some(_) => response.with_vary("Save-Data"),
+        None => response,
+    };
+    let policy = match immutable {
+        true => Some(CACHE_FOREVER_IMMUTABLE),
+        false => cache_control(args.cache_profile, p),
+    };
+    match (&response.status, policy) {
+        (Status::Success | Status::PartialContent | Status::NotModified, Some(policy)) => {
+            response.with_header("Cache-Control", policy.to_string())
+        }
+        _ => response,
+    }
+}
+
+/// The host named by a `Host: ` header line, without its port. None for any
+/// other line, and for a Host whose port is not a number.
+fn parse_host_address(host_str: &str) -> Option<&str> {
+    host_str
+        .trim_end()
+        .strip_prefix("Host: ")
+        .and_then(|x| x.strip_prefix("http://").or(Some(x)))
+        .and_then(|x| x.split('/').next())
+        .and_then(|x| {
+            let (host, port) = match x.find(']') {
+                // IPv6 literal, its colons are not a port separator
+                Some(end) if x.starts_with('[') => match &x[end + 1..] {
+                    "" => (&x[..=end], ""),
+                    rest => (&x[..=end], rest.strip_prefix(':')?),
+                },
+                _ => x.split_once(':').unwrap_or((x, "")),
+            };
+            // the port is not used, but a Host that is not one names no server
+            port.bytes().all(|b| b.is_ascii_digit()).then_some(host)
+        })
+        // `example.com.` is the same host as `example.com`, and the same vhost directory
+        .map(|x| x.strip_suffix('.').unwrap_or(x))
+}
+
+/// Canonical form used to compare hosts: lowercase, without the FQDN trailing
+/// dot and without IPv6 brackets. The port is already gone after parsing.
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.')
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase()
+}
+
+// `.example.com` allows every subdomain of example.com, other entries are exact.
+// IP literals only ever match an exact entry.
+fn host_allowed(allowed_hosts: &[String], host: &str) -> bool {
+    let host = normalize_host(host);
+    let is_ip_literal = host.parse::<std::net::IpAddr>().is_ok();
+    allowed_hosts
+        .iter()
+        .any(|allowed| match allowed.strip_prefix('.') {
+            Some(suffix) if !is_ip_literal => host == suffix || host.ends_with(allowed.as_str()),
+            _ => host == *allowed,
+        })
+}
+
+// How long a connection may sit idle waiting for its next request, and how long
+// reading a request may stall once it has started arriving.
+const DEFAULT_KEEP_ALIVE_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 1000;
+const MAX_REQUESTS_PER_CONNECTION: u32 = 100;
+
+enum ReadRequestInitialError {
+    Io(io::Error),
+    Timeout,
+    ClientClosed,
+    EmptyRequest,
+    HeaderReadIo(io::Error),
+}
+
+/// Why a connection ended, reported in its closing summary.
+#[derive(Clone, Copy)]
+enum CloseReason {
+    ClientClosed,
+    ConnectionClose,
+    MaxRequests,
+    Timeout,
+    ReadError,
+    WriteError,
+    // the client went away mid-response, e.g. a cancelled download
+    ClientAborted,
+    Shutdown,
+}
+
+impl CloseReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client-closed",
+            CloseReason::ConnectionClose => "connection-close",
+            CloseReason::MaxRequests => "max-requests",
+            CloseReason::Timeout => "timeout",
+            CloseReason::ReadError => "read-error",
+            CloseReason::WriteError => "write-error",
+            CloseReason::ClientAborted => "client-aborted",
+            CloseReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Classifies a failed response write. A client disconnecting first is routine
+/// (navigating away mid-download) and only noted in debug builds.
+fn write_failure(e: io::Error) -> CloseReason {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => {
+            #[cfg(debug_assertions)]
+            println!("Client disconnected during the response: {}", e);
+            CloseReason::ClientAborted
+        }
+        _ => {
+            log_error!(
+                "Failed to write response to stream: {}. Closing connection.",
+                e
+            );
+            CloseReason::WriteError
+        }
+    }
+}
+
+/// Totals for one connection, bytes as they crossed the socket.
+#[derive(Default)]
+struct ConnectionStats {
+    requests: u32,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl ConnectionStats {
+    #[cfg_attr(not(debug_assertions), allow(dead_code))]
+    fn summary(&self, addr: SocketAddrV4, reason: CloseReason) -> String {
+        format!(
+            "Connection with {} closed: reason={} requests={} bytes_read={} bytes_written={}",
+            addr,
+            reason.as_str(),
+            self.requests,
+            self.bytes_read,
+            self.bytes_written
+        )
+    }
+}
+
+/// Counts the bytes passing through a reader or writer.
+struct Counted<T> {
+    inner: T,
+    bytes: u64,
+}
+
+impl<T> Counted<T> {
+    fn new(inner: T) -> Self {
+        Counted { inner, bytes: 0 }
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// A write blocks for at most the timeout set on the stream and is retried that
+// many times, so a client that takes no bytes for 10s is dropped rather than
+// holding its thread forever.
+#[cfg(not(feature = "async"))]
+const WRITE_TIMEOUT_MS: u64 = 200;
+#[cfg(not(feature = "async"))]
+const WRITE_RETRIES: u32 = 50;
+
+/// Retries writes that were interrupted or that timed out on a backpressured
+/// socket, so a slow reader stalls the response rather than truncating it. A
+/// timeout is `WouldBlock` on unix and `TimedOut` on windows. Partial writes are
+/// left to `write_all` on top of it.
+#[cfg(not(feature = "async"))]
+struct Retrying<W> {
+    inner: W,
+}
+
+#[cfg(not(feature = "async"))]
+impl<W: Write> Write for Retrying<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut waits = 0;
+        loop {
+            match self.inner.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) && waits < WRITE_RETRIES =>
+                {
+                    waits += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An HTTP/0.9 simple request, `GET /path` without a version. Its client sends no
+/// header section and waits for the response right after the request line.
+fn is_simple_request(request_line: &str) -> bool {
+    matches!(
+        request_line
+            .trim()
+            .split(' ')
+            .collect::<Vec<_>>()
+            .as_slice(),
+        ["GET", _]
+    )
+}
+
+/// Waits, within the read timeout set on the stream, for the first byte of the
+/// next request. Pipelined requests are usually in the buffer already.
+#[cfg(not(feature = "async"))]
+fn await_request(rdr: &mut BufReader<Counted<&TcpStream>>) -> Result<(), ReadRequestInitialError> {
+    match rdr.fill_buf() {
+        Ok([]) => Err(ReadRequestInitialError::ClientClosed),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            Err(ReadRequestInitialError::Timeout)
+        }
+        Err(e) => Err(ReadRequestInitialError::Io(e)),
+    }
+}
+
+/// The request line and header lines of a request, and whether any of them
+/// ended in a bare LF instead of CRLF.
+type RequestHead = (String, Vec<String>, bool);
+
+#[cfg(not(feature = "async"))]
+fn read_request_line_and_headers(
+    rdr: &mut BufReader<Counted<&TcpStream>>,
+) -> Result<RequestHead, ReadRequestInitialError> {
+    let mut request_line_str = String::new();
+    match rdr.read_line(&mut request_line_str) {
+        Ok(0) => return Err(ReadRequestInitialError::ClientClosed),
+        Ok(_) => {
+            if request_line_str.trim().is_empty() {
+                return Err(ReadRequestInitialError::EmptyRequest);
+            }
+        }
+        Err(e) => {
+            return if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
+                Err(ReadRequestInitialError::Timeout)
+            } else {
+                Err(ReadRequestInitialError::Io(e))
+            };
+        }
+    }
+    let mut bare_lf = !request_line_str.ends_with("\r\n");
+    if is_simple_request(&request_line_str) {
+        return Ok((request_line_str, vec![], bare_lf));
+    }
+
+    let mut actual_headers = Vec::new();
+    loop {
+        let mut header_line = String::new();
+        match rdr.read_line(&mut header_line) {
+            Ok(0) => break,
+            Ok(_) => {
+                bare_lf |= !header_line.ends_with("\r\n");
+                if header_line.trim().is_empty() {
+                    break;
+                }
+                // kept verbatim (minus the line terminator) so TRACE can echo it faithfully
+                actual_headers.push(header_line.trim_end_matches(['\r', '\n']).to_string());
+            }
+            Err(e) => return Err(ReadRequestInitialError::HeaderReadIo(e)),
+        }
+    }
+    Ok((request_line_str, actual_headers, bare_lf))
+}
+
+/// Whether `--allow-methods` and `--deny-methods` let `method` through. Without
+/// either, every method goes on to be served or refused as usual.
+fn method_permitted(args: &ProgArgs, method: &str) -> bool {
+    args.allow_methods
+        .as_ref()
+        .is_none_or(|allowed| allowed.iter().any(|allowed| allowed == method))
+        && !args.deny_methods.iter().any(|denied| denied == method)
+}
+
+fn allowed_methods(args: &ProgArgs) -> String {
+    let mut methods = vec!["GET", "HEAD", "OPTIONS"];
+    if args.allow_trace {
+        methods.push("TRACE");
+    }
+    if args.webdav_readonly {
+        methods.push("PROPFIND");
+    }
+    methods.retain(|method| method_permitted(args, method));
+    methods.join(", ")
+}
+
+// class 1 is the baseline every WebDAV server claims, locking (class 2) is not offered
+fn advertise_dav(response: Response, args: &ProgArgs) -> Response {
+    match response.status {
+        Status::NoContent if args.webdav_readonly => response.with_header("DAV", "1".to_string()),
+        _ => response,
+    }
+}
+
+// credentials must not be reflected back, a TRACE echo is readable by scripts
+const TRACE_REDACTED_HEADERS: [&str; 3] = ["Authorization", "Proxy-Authorization", "Cookie"];
+
+fn build_trace_response(request_line_str: &str, actual_headers: &[String]) -> Response {
+    let mut echo = String::new();
+    echo.push_str(request_line_str.trim_end_matches(['\r', '\n']));
+    echo.push_str("\r\n");
+    for header in actual_headers {
+        let name = header.split(':').next().unwrap_or_default().trim();
+        if TRACE_REDACTED_HEADERS
+            .iter()
+            .any(|redacted| redacted.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        echo.push_str(header);
+        echo.push_str("\r\n");
+    }
+    echo.push_str("\r\n");
+    build_http_response(
+        Status::Success,
+        "message/http",
+        Cow::Owned(echo.into_bytes()),
+    )
+}
+
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Serves an HTTP-01 challenge response. Tokens have no extension, so they
+/// cannot go through `serve_file`.
+fn serve_acme_challenge(dir: &Path, token: &str) -> Response {
+    // tokens are base64url, which also rules out any path trickery
+    let is_token = !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !is_token {
+        return build_error_response(Status::PageNotFound, Framing::Safe);
+    }
+    let p = dir.join(token);
+    match fs::read(&p) {
+        Ok(key_authorization) => build_http_response(
+            Status::Success,
+            "application/octet-stream",
+            Cow::Owned(key_authorization),
+        )
+        .with_header("Cache-Control", "no-store".to_string()),
+        Err(e) => e_to_cow(&p, e),
+    }
+}
+
+/// The rest of `resource` below `prefix`, which only matches whole path segments:
+/// `/static` covers `/static` and `/static/app.js` but not `/statics`.
+fn strip_path_prefix<'a>(resource: &'a str, prefix: &str) -> Option<&'a str> {
+    match resource.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// Splits the `--strip-prefix` off `resource`, None when the target lies outside it.
+fn strip_prefix<'a>(args: &'a ProgArgs, resource: &'a str) -> Option<(&'a str, &'a str)> {
+    match &args.strip_prefix {
+        Some(prefix) => strip_path_prefix(resource, prefix).map(|rest| (prefix.as_str(), rest)),
+        None => Some(("", resource)),
+    }
+}
+
+/// The `--mount` covering `resource` as (prefix, directory, rest of the path).
+/// Mounts are kept longest prefix first, so the first match is the most specific.
+fn find_mount<'a>(
+    args: &'a ProgArgs,
+    resource: &'a str,
+) -> Option<(&'a str, &'a PathBuf, &'a str)> {
+    args.mounts.iter().find_map(|(prefix, dir)| {
+        strip_path_prefix(resource, prefix).map(|rest| (prefix.as_str(), dir, rest))
+    })
+}
+
+/// The directory serving `domain_name` in vhost mode, falling back to the
+/// `--default-vhost` site for hosts without one.
+fn vhost_root(args: &ProgArgs, domain_name: &str) -> Option<PathBuf> {
+    // the host becomes a path component, a dot segment would leave the base directory
+    let host_dir = match domain_name {
+        "" | "." | ".." => None,
+        _ => Some(args.directory.join(domain_name)).filter(|p| p.is_dir()),
+    };
+    host_dir.or_else(|| args.default_vhost.clone())
+}
+
+/// The port a client put in its Host header, kept when sending it elsewhere.
+fn host_port(actual_headers: &[String]) -> Option<&str> {
+    let host = find_header(actual_headers, "Host")?;
+    // past the brackets of an IPv6 literal
+    let rest = host.rfind(']').map_or(host, |end| &host[end + 1..]);
+    let (_, port) = rest.rsplit_once(':')?;
+    Some(port).filter(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The scheme the client used. This server only speaks plain HTTP, `https` is a
+/// proxy in front saying it terminated TLS, which only `--trust-proxy` believes.
+/// Of a chain of proxies the first one faced the client.
+fn request_scheme(args: &ProgArgs, actual_headers: &[String]) -> &'static str {
+    let forwarded = find_header(actual_headers, "X-Forwarded-Proto")
+        .filter(|_| args.trust_proxy)
+        .and_then(|protos| protos.split(',').next());
+    match forwarded {
+        Some(proto) if proto.trim().eq_ignore_ascii_case("https") => "https",
+        _ => "http",
+    }
+}
+
+/// `--canonical-host`: the same target on the one host name the site answers to.
+fn canonical_host_redirect(args: &ProgArgs, resource: &str, actual_headers: &[String]) -> Response {
+    let canonical = args.canonical_host.as_deref().unwrap_or_default();
+    let scheme = request_scheme(args, actual_headers);
+    let url = match host_port(actual_headers) {
+        Some(port) => format!("{}://{}:{}{}", scheme, canonical, port, resource),
+        None => format!("{}://{}{}", scheme, canonical, resource),
+    };
+    match Status::redirect(301, url) {
+        Some(status) => build_redirect_response(status),
+        None => build_error_response(Status::InternalServerError, Framing::Safe),
+    }
+}
+
+/// What a request target maps to once the Host, `--strip-prefix` and mounts
+/// have been taken into account.
+enum Target<'a> {
+    /// `resource` below `root`, then below each of `fallbacks`, redirects are
+    /// built from `url_base`
+    Tree {
+        root: PathBuf,
+        fallbacks: &'a [PathBuf],
+        resource: &'a str,
+        url_base: String,
+    },
+    /// an ACME token and the directory it is looked up in
+    AcmeChallenge(PathBuf, &'a str),
+    SingleFile,
+}
+
+/// The host a request is for, once it has passed `--allowed-hosts` and
+/// `--canonical-host`. Everything but the internal endpoints answers only after
+/// this, so no host the server should not serve gets a route, redirect or file.
+fn check_host<'a>(
+    resource: &str,
+    actual_headers: &'a [String],
+    args: &ProgArgs,
+) -> Result<&'a str, Response> {
+    let domain_name_option = actual_headers
+        .iter()
+        .find_map(|h_str| parse_host_address(h_str.as_str()));
+
+    match domain_name_option {
+        // `Host: :8080` names no host, it must not fall back to the base directory
+        Some(domain_name) if domain_name.trim().is_empty() => {
+            log_error!("Rejecting request with an empty Host header.");
+            Err(build_error_response(Status::BadRequest, Framing::Safe))
+        }
+        Some(domain_name)
+            if args
+                .allowed_hosts
+                .as_ref()
+                .is_some_and(|allowed| !host_allowed(allowed, domain_name)) =>
+        {
+            log_error!("Rejecting request for unexpected host: {}", domain_name);
+            Err(build_error_response(
+                Status::MisdirectedRequest,
+                Framing::Safe,
+            ))
+        }
+        Some(domain_name)
+            if args.canonical_host.as_ref().is_some_and(|canonical| {
+                normalize_host(canonical) != normalize_host(domain_name)
+            }) =>
+        {
+            Err(canonical_host_redirect(args, resource, actual_headers))
+        }
+        Some(domain_name) => Ok(domain_name),
+        None => {
+            log_error!("Host header not found or unparseable.");
+            Err(build_error_response(Status::BadRequest, Framing::Safe))
+        }
+    }
+}
+
+/// Maps a request for `domain_name`, already through [`check_host`], to what
+/// serves it.
+fn locate<'a>(
+    resource: &'a str,
+    domain_name: &str,
+    args: &'a ProgArgs,
+    server_listening_addr: SocketAddrV4,
+) -> Result<Target<'a>, Response> {
+    if args.single_file {
+        return Ok(Target::SingleFile);
+    }
+    let root = if args.vhosts {
+        vhost_root(args, domain_name)
+    } else {
+        Some(args.directory.clone())
+    };
+    if let Some(token) = resource.strip_prefix(ACME_CHALLENGE_PREFIX) {
+        // a separate challenge directory also answers hosts without a site yet
+        let dir = args
+            .acme_challenge_dir
+            .clone()
+            .or_else(|| root.map(|root| root.join(".well-known").join("acme-challenge")));
+        return match dir {
+            Some(dir) => Ok(Target::AcmeChallenge(dir, token)),
+            None => Err(build_error_response(Status::PageNotFound, Framing::Safe)),
+        };
+    }
+    let (prefix, resource) = match strip_prefix(args, resource) {
+        Some(stripped) => stripped,
+        None => return Err(build_error_response(Status::PageNotFound, Framing::Safe)),
+    };
+    // the fallbacks mirror the document root, a mount is a tree of its own
+    let (mount, root, fallbacks, resource) = match find_mount(args, resource) {
+        Some((mount, dir, rest)) => (mount, dir.clone(), &[][..], rest),
+        None => match root {
+            Some(root) => ("", root, args.fallback_roots.as_slice(), resource),
+            None => {
+                log_error!("No document root for host {}", domain_name);
+                return Err(build_error_response(Status::PageNotFound, Framing::Safe));
+            }
+        },
+    };
+    // redirects are built from this, so they keep the externally visible prefixes
+    let url_base = format!(
+        "http://{}:{}{}{}",
+        domain_name,
+        server_listening_addr.port(),
+        prefix,
+        mount
+    );
+    Ok(Target::Tree {
+        root,
+        fallbacks,
+        resource,
+        url_base,
+    })
+}
+
+/// The site's own `404.html` at `root`, the convention of static site
+/// generators, sent with the 404 status in place of the built-in page. None when
+/// there is no such file.
+fn not_found_page(root: &Path, args: &ProgArgs) -> Option<Response> {
+    let p = resolve_path(root, NOT_FOUND_PAGE, args.follow_symlinks)?;
+    let body = fs::read(p).ok()?;
+    let response = build_http_response(
+        Status::PageNotFound,
+        &content_type(args, "html"),
+        Cow::Owned(body),
+    );
+    Some(response.with_header("Cache-Control", "no-store".to_string()))
+}
+
+/// Serves the file an upstream app named in X-Accel-Redirect, relative to
+/// `--internal-root`, in place of the requested URL. The app decides who may
+/// download what and this server delivers it. Every client able to connect can
+/// send the header, so `--trust-proxy` is only safe when the listener is reached
+/// through a proxy that strips it from client requests.
+fn serve_internal_redirect(
+    root: &Path,
+    target: &str,
+    method: &str,
+    req_headers: &[String],
+    args: &ProgArgs,
+) -> Response {
+    let path = target.split('?').next().unwrap_or_default();
+    let p = match resolve_path(root, path, args.follow_symlinks) {
+        Some(p) => p,
+        None => {
+            log_error!("Refusing internal redirect to {:?}", target);
+            return build_error_response(Status::Forbidden, Framing::Safe);
+        }
+    };
+    if p.is_dir() {
+        return build_error_response(Status::PageNotFound, Framing::Safe);
+    }
+    serve_file(&p, method, req_headers, None, args)
+}
+
+fn handle_get(
+    method: &str,
+    resource: &str,
+    actual_headers: &[String],
+    args: &ProgArgs,
+    server_listening_addr: SocketAddrV4,
+) -> Response {
+    if let Some(response) = args
+        .internal_router
+        .as_ref()
+        .and_then(|router| router.route(resource, actual_headers))
+    {
+        return response;
+    }
+    let domain_name = match check_host(resource, actual_headers, args) {
+        Ok(domain_name) => domain_name,
+        Err(response) => return response,
+    };
+    if let Some(response) = args.maintenance.response() {
+        return response;
+    }
+    if let (true, Some(root), Some(target)) = (
+        args.trust_proxy,
+        &args.internal_root,
+        find_header(actual_headers, "X-Accel-Redirect"),
+    ) {
+        return serve_internal_redirect(root, target, method, actual_headers, args);
+    }
+    if let Some(route) = args.routes.iter().find(|route| route.matches(resource)) {
+        return route.response();
+    }
+    if let Some(status) = args
+        .redirects
+        .as_ref()
+        .and_then(|redirects| redirects.find(resource))
+    {
+        return build_redirect_response(status);
+    }
+
+    let forced_type = normalize_url_path(resource).and_then(|path| {
+        args.type_overrides
+            .iter()
+            .find(|(overridden, _)| *overridden == path)
+            .map(|(_, content_type)| content_type.as_str())
+    });
+    match locate(resource, domain_name, args, server_listening_addr) {
+        Ok(Target::Tree {
+            root,
+            fallbacks,
+            resource,
+            url_base,
+        }) => {
+            let roots: Vec<&Path> = std::iter::once(root.as_path())
+                .chain(fallbacks.iter().map(PathBuf::as_path))
+                .collect();
+            let response = handle_request(
+                &roots,
+                resource,
+                url_base,
+                method,
+                actual_headers,
+                forced_type,
+                args,
+            );
+            match response.status {
+                Status::PageNotFound if args.not_found_page => roots
+                    .iter()
+                    .find_map(|root| not_found_page(root, args))
+                    .unwrap_or(response),
+                _ => response,
+            }
+        }
+        Ok(Target::AcmeChallenge(dir, token)) => serve_acme_challenge(&dir, token),
+        Ok(Target::SingleFile) => {
+            if resource == "/" || args.spa {
+                serve_file(&args.directory, method, actual_headers, forced_type, args)
+            } else {
+                build_error_response(Status::PageNotFound, Framing::Safe)
+            }
+        }
+        Err(response) => response,
+    }
+}
+
+/// Attaches the `--preload` Link headers and the `--preload-manifest` ones for
+/// `resource` to a successful HTML response.
+fn add_preload_links(response: Response, resource: &str, args: &ProgArgs) -> Response {
+    let page_links = args
+        .preload
+        .as_ref()
+        .map_or(&[][..], |manifest| manifest.links(resource));
+    if args.preload_links.is_empty() && page_links.is_empty() {
+        return response;
+    }
+    let is_html = response.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("Content-Type") && value.starts_with("text/html")
+    });
+    if !matches!(response.status, Status::Success) || !is_html {
+        return response;
+    }
+    let mut links: Vec<&String> = args.preload_links.iter().collect();
+    for link in page_links {
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links.into_iter().fold(response, |response, link| {
+        response.with_header("Link", link.clone())
+    })
+}
+
+/// Drops a `#fragment` from a request target. Clients are meant to keep it to
+/// themselves, but some send it, and it must not end up in a file name.
+fn strip_fragment(target: &str) -> &str {
+    match target.split_once('#') {
+        Some((target, _fragment)) => {
+            #[cfg(debug_assertions)]
+            println!("Dropping fragment sent by client: {}", _fragment);
+            target
+        }
+        None => target,
+    }
+}
+
+/// The authority an absolute-form target names, which stands in for the Host
+/// header (RFC 9112, section 3.2.2), and the rest of it: `http://example.com/a?b`
+/// gives `/a?b`, `http://example.com?b` just `?b`. None for any other form.
+fn absolute_form(target: &str) -> Option<(&str, &str)> {
+    let scheme_len = ["http://", "https://"].into_iter().find_map(|scheme| {
+        let prefix = target.get(..scheme.len())?;
+        prefix.eq_ignore_ascii_case(scheme).then_some(scheme.len())
+    })?;
+    let rest = &target[scheme_len..];
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    (!authority.is_empty()).then_some((authority, path))
+}
+
+fn determine_http_response(
+    request_line_str: &str,
+    actual_headers: &[String],
+    args: &ProgArgs,
+    server_listening_addr: SocketAddrV4,
+) -> Response {
+    let started = Instant::now();
+    let mut parts: Vec<&str> = request_line_str
+        .trim()
+        .split(' ')
+        .enumerate()
+        .map(|(i, part)| if i == 1 { strip_fragment(part) } else { part })
+        .collect();
+    // from here on an absolute-form request is the origin-form one with that Host
+    let (origin, with_authority): (String, Vec<String>);
+    let actual_headers = match parts.get(1).and_then(|target| absolute_form(target)) {
+        Some((authority, path)) => {
+            // `http://example.com` and `http://example.com?b` ask for the root
+            origin = match path.starts_with('/') {
+                true => path.to_string(),
+                false => format!("/{}", path),
+            };
+            parts[1] = &origin;
+            with_authority = actual_headers
+                .iter()
+                .filter(|h| find_header(std::slice::from_ref(h), "Host").is_none())
+                .cloned()
+                .chain([format!("Host: {}", authority)])
+                .collect();
+            &with_authority
+        }
+        None => actual_headers,
+    };
+    let response = match parts.as_slice() {
+        _ if has_malformed_headers(actual_headers) => {
+            build_error_response(Status::BadRequest, Framing::Unsafe)
+        }
+        [method, _, "HTTP/1.1"] if !method_permitted(args, method) => {
+            build_error_response(Status::MethodNotAllowed, Framing::Safe)
+                .with_header("Allow", allowed_methods(args))
+        }
+        ["OPTIONS", "*", "HTTP/1.1"] => {
+            let response = Response {
+                status: Status::NoContent,
+                headers: vec![("Allow".to_string(), allowed_methods(args))],
+                body: Body::None,
+            };
+            advertise_dav(response, args)
+        }
+        // an empty or relative target would be pushed onto the document root as is,
+        // PROPFIND only counts when it is served at all
+        [method @ ("GET" | "HEAD" | "OPTIONS" | "PROPFIND"), resource, "HTTP/1.1"]
+            if !resource.starts_with('/') && (*method != "PROPFIND" || args.webdav_readonly) =>
+        {
+            log_error!("Rejecting {} target: {:?}", method, resource);
+            build_error_response(Status::BadRequest, Framing::Safe)
+        }
+        ["GET", resource, "HTTP/1.1"] => {
+            let response = handle_get("GET", resource, actual_headers, args, server_listening_addr);
+            add_preload_links(response, resource, args)
+        }
+        ["HEAD", resource, "HTTP/1.1"] => {
+            let response = handle_get(
+                "HEAD",
+                resource,
+                actual_headers,
+                args,
+                server_listening_addr,
+            );
+            // the body is dropped once the response is final, below
+            add_preload_links(response, resource, args)
+        }
+        ["OPTIONS", resource, "HTTP/1.1"] => match args
+            .cors
+            .as_ref()
+            .filter(|cors| cors.is_preflight(actual_headers))
+        {
+            Some(cors) => cors.preflight(actual_headers, allowed_methods(args)),
+            None => {
+                let response = handle_get(
+                    "OPTIONS",
+                    resource,
+                    actual_headers,
+                    args,
+                    server_listening_addr,
+                )
+                .into_options(allowed_methods(args));
+                advertise_dav(response, args)
+            }
+        },
+        ["PROPFIND", resource, "HTTP/1.1"] if args.webdav_readonly => {
+            let located = check_host(resource, actual_headers, args).and_then(|domain_name| {
+                match args.maintenance.response() {
+                    Some(response) => Err(response),
+                    None => locate(resource, domain_name, args, server_listening_addr),
+                }
+            });
+            match located {
+                Ok(Target::Tree {
+                    root,
+                    resource,
+                    url_base,
+                    ..
+                }) => webdav::propfind(&root, resource, &url_base, actual_headers, args),
+                // challenge tokens and a lone file are not a tree to browse
+                Ok(_) => build_error_response(Status::MethodNotAllowed, Framing::Safe)
+                    .with_header("Allow", "GET, HEAD, OPTIONS".to_string()),
+                Err(response) => response,
+            }
+        }
+        ["TRACE", _, "HTTP/1.1"] if args.allow_trace => {
+            build_trace_response(request_line_str, actual_headers)
+        }
+        ["GET", resource] => {
+            log_error!(
+                "Rejecting HTTP/0.9 request for {:?}: only HTTP/1.1 is served",
+                resource
+            );
+            build_error_response(Status::BadRequest, Framing::Unsafe)
+        }
+        ["TRACE", _, "HTTP/1.1"] => build_error_response(Status::MethodNotAllowed, Framing::Safe)
+            .with_header("Allow", allowed_methods(args)),
+        [_, _, version] if version.starts_with("HTTP/") => {
+            log_error!("Unsupported request: {}", request_line_str.trim());
+            build_error_response(Status::NotImplemented, Framing::Safe)
+        }
+        _ => {
+            log_error!("Malformed request line: {}", request_line_str.trim());
+            build_error_response(Status::BadRequest, Framing::Unsafe)
+        }
+    };
+    let response = match args.error_format {
+        ErrorFormat::Json => json_error(response),
+        // either page answers an error, which one depends on Accept
+        ErrorFormat::Auto if response.code() >= 400 => {
+            let response = response.with_vary("Accept");
+            match prefers_json(find_header(actual_headers, "Accept")) {
+                true => json_error(response),
+                false => response,
+            }
+        }
+        _ => response,
+    };
+    let response = match &args.cors {
+        Some(cors) => cors.apply(response, actual_headers),
+        None => response,
+    };
+    let response = match request_line_str.split(' ').next() {
+        Some("HEAD") => response.into_head(),
+        _ => response,
+    };
+    let response = match response.closes_connection() {
+        false if args.no_keep_alive => response.with_header("Connection", "close".to_string()),
+        _ => response,
+    };
+    if !args.timing_header {
+        return response;
+    }
+    // Bodies are produced while they are written, after these headers are out, so
+    // this is the time to the response head: routing, metadata and opening files,
+    // but not reading or sending them. The names say so rather than claim a total.
+    let ms = started.elapsed().as_secs_f64() * 1000.0;
+    response
+        .with_header("Server-Timing", format!("head;dur={:.3}", ms))
+        .with_header("X-Response-Head-Time", format!("{:.3}ms", ms))
+}
+
+/// `--debug-headers`: where the connection stands against
+/// MAX_REQUESTS_PER_CONNECTION, `served` counting the request being answered.
+fn with_request_counts(response: Response, served: u32) -> Response {
+    let remaining = MAX_REQUESTS_PER_CONNECTION.saturating_sub(served);
+    response
+        .with_header("X-Requests-Served", served.to_string())
+        .with_header("X-Requests-Remaining", remaining.to_string())
+}
+
+#[cfg(not(feature = "async"))]
+// returns the body length for the access log and everything written for the stats
+fn write_response_to_stream(
+    stream: &TcpStream,
+    response: Response,
+    early_hints: bool,
+) -> Result<(u64, u64), io::Error> {
+    let mut writer = io::BufWriter::new(Counted::new(Retrying { inner: stream }));
+    if let Some(hints) = response.early_hints().filter(|_| early_hints) {
+        writer.write_all(&hints)?;
+        writer.flush()?;
+    }
+    let body_len = response.write_to(&mut writer)?;
+    writer.flush()?;
+    Ok((body_len, writer.get_ref().bytes))
+}
+
+/// `--tcp-keepalive`: the OS probes a connection idle for `idle` and drops it
+/// once the peer has gone away without a word, e.g. behind a NAT that forgot it.
+fn enable_tcp_keepalive(socket: socket2::SockRef<'_>, idle: Duration) {
+    let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+    if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+        log_error!("Failed to enable TCP keepalive: {}", e);
+    }
+}
+
+/// Counts a new connection against its client. A client already at
+/// `--max-connections-per-ip` is turned away with the returned 503 instead.
+fn admit_client(
+    args: &ProgArgs,
+    peer: Option<std::net::IpAddr>,
+) -> Result<Option<client_limit::ClientSlot<'_>>, Response> {
+    match (&args.client_limit, peer) {
+        (Some(limit), Some(ip)) => match limit.acquire(ip) {
+            Some(slot) => Ok(Some(slot)),
+            None => {
+                log_error!(
+                    "Rejecting connection from {}: too many open connections",
+                    ip
+                );
+                metrics::connection_rejected();
+                Err(build_error_response(
+                    Status::ServiceUnavailable,
+                    Framing::Unsafe,
+                ))
+            }
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Counts a request against `--global-rate-limit`. Past the ceiling it is
+/// answered with the returned 503, which closes the connection. Under
+/// `--strict-crlf` a head with a bare LF line ending is refused with a 400 first,
+/// a proxy in front may have split it into lines differently.
+fn admit_request(args: &ProgArgs, bare_lf: bool) -> Result<(), Response> {
+    if args.strict_crlf && bare_lf {
+        log_error!("Rejecting request with a bare LF line ending");
+        return Err(build_error_response(Status::BadRequest, Framing::Unsafe));
+    }
+    match &args.rate_limit {
+        Some(limit) if !limit.try_acquire() => {
+            log_error!("Rejecting request: global rate limit reached");
+            metrics::request_throttled();
+            // the bucket holds a second's worth of requests
+            Err(
+                build_error_response(Status::ServiceUnavailable, Framing::Unsafe)
+                    .with_header("Retry-After", "1".to_string()),
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "async"))]
+fn handle_connection(
+    args: &ProgArgs,
+    tracker: &shutdown::ConnectionTracker,
+    stream: TcpStream,
+    addr: SocketAddrV4,
+) {
+    let connection_id = log_context::next_connection();
+    let admitted = {
+        let _context = log_context::enter(log_context::RequestId {
+            connection: connection_id,
+            request: 1,
+        });
+        if let Some(idle) = args.tcp_keepalive {
+            enable_tcp_keepalive(socket2::SockRef::from(&stream), idle);
+        }
+        if let Err(e) = stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS))) {
+            log_error!("Failed to set write timeout: {}", e);
+        }
+        admit_client(args, stream.peer_addr().ok().map(|peer| peer.ip()))
+    };
+    let _client = match admitted {
+        Ok(slot) => slot,
+        Err(response) => {
+            write_response_to_stream(&stream, response, false).ok();
+            stream.shutdown(std::net::Shutdown::Both).ok();
+            return;
+        }
+    };
+    let connection = tracker.register(&stream);
+    let mut stats = ConnectionStats::default();
+    // one reader for the whole connection, pipelined requests may already sit in its buffer
+    let mut rdr = BufReader::new(Counted::new(&stream));
+
+    let reason = loop {
+        let _context = log_context::enter(log_context::RequestId {
+            connection: connection_id,
+            request: stats.requests + 1,
+        });
+
+        if let Err(e) = stream.set_read_timeout(Some(args.keep_alive_timeout)) {
+            log_error!("Failed to set read timeout: {}. Closing connection.", e);
+            break CloseReason::ReadError;
+        }
+
+        // checked after the timeout is reset, so the shorter one set by the
+        // drain cannot be overwritten by a connection that missed the flag
+        if shutdown::requested() {
+            break CloseReason::Shutdown;
+        }
+
+        // the rest of the request, its body included, gets the shorter timeout
+        let head = await_request(&mut rdr).and_then(|()| {
+            stream
+                .set_read_timeout(Some(args.request_timeout))
+                .map_err(ReadRequestInitialError::Io)?;
+            read_request_line_and_headers(&mut rdr)
+        });
+        stats.bytes_read = rdr.get_ref().bytes;
+        let (request_line_str, actual_headers, bare_lf) = match head {
+            Ok(parts) => parts,
+            // idle connections are woken up with an end of stream by the drain
+            Err(_) if shutdown::requested() => break CloseReason::Shutdown,
+            Err(ReadRequestInitialError::ClientClosed)
+            | Err(ReadRequestInitialError::EmptyRequest) => break CloseReason::ClientClosed,
+            Err(ReadRequestInitialError::Timeout) => break CloseReason::Timeout,
+            Err(ReadRequestInitialError::Io(e)) => {
+                log_error!("Failed to read request line: {}. Closing connection.", e);
+                break CloseReason::ReadError;
+            }
+            Err(ReadRequestInitialError::HeaderReadIo(e)) => {
+                log_error!("Error reading headers: {}. Closing connection.", e);
+                break CloseReason::ReadError;
+            }
+        };
+
+        #[cfg(debug_assertions)]
+        {
+            println!("--- New Request ---");
+            println!("Request Line: {}", request_line_str.trim());
+            println!("Headers: {:#?}", actual_headers);
+        }
+
+        let _request = tracker.begin_request(connection.as_ref());
+
+        let response = match admit_request(args, bare_lf) {
+            Ok(()) => {
+                let drained = match body::request_body(&actual_headers, args.max_body_size) {
+                    Ok(request_body) => {
+                        // the body is read in full before answering, whatever the response,
+                        // so the next request starts where it should
+                        if body::expects_continue(&actual_headers) && !request_body.is_empty() {
+                            if let Err(e) = (Retrying { inner: &stream }).write_all(body::CONTINUE)
+                            {
+                                break write_failure(e);
+                            }
+                            stats.bytes_written += body::CONTINUE.len() as u64;
+                        }
+                        body::drain(&mut rdr, request_body, args.max_body_size)
+                    }
+                    Err(status) => Err(body::BodyError::Rejected(status)),
+                };
+                stats.bytes_read = rdr.get_ref().bytes;
+                match drained {
+                    Ok(()) => {
+                        determine_http_response(&request_line_str, &actual_headers, args, addr)
+                    }
+                    Err(body::BodyError::Rejected(status)) => {
+                        build_error_response(status, Framing::Unsafe)
+                    }
+                    Err(body::BodyError::Io(e)) => {
+                        log_error!("Failed to drain request body: {}. Closing connection.", e);
+                        break CloseReason::ReadError;
+                    }
+                }
+            }
+            // the connection closes, its body is never read
+            Err(response) => response,
+        };
+        let response = match args.debug_headers {
+            true => with_request_counts(response, stats.requests + 1),
+            false => response,
+        };
+        // the drain has begun, this response is the last one on the connection
+        let draining = shutdown::requested();
+        // as it is once the cap is reached, the client must not send another
+        let last = stats.requests + 1 >= MAX_REQUESTS_PER_CONNECTION;
+        let response = match response.closes_connection() {
+            false if draining || last => response.with_header("Connection", "close".to_string()),
+            _ => response,
+        };
+
+        let wants_close = response.closes_connection() || wants_close(&actual_headers);
+        let status = response.code();
+
+        // written and flushed in full before the next pipelined request is read, a
+        // client that does not read its responses stalls the loop on the socket
+        // instead of having them pile up in memory
+        let body_len = match write_response_to_stream(&stream, response, args.early_hints) {
+            Ok((body_len, written)) => {
+                stats.bytes_written += written;
+                body_len
+            }
+            Err(e) => break write_failure(e),
+        };
+
+        if let Some(format) = args.log_format {
+            let entry = access_log::AccessEntry {
+                remote: stream.peer_addr().ok().map(|peer| peer.ip()),
+                request_line: &request_line_str,
+                headers: &actual_headers,
+                status,
+                bytes: body_len,
+            };
+            access_log::write(args, format, &entry);
+        }
+
+        stats.requests += 1;
+
+        if draining {
+            break CloseReason::Shutdown;
+        }
+        if last {
+            break CloseReason::MaxRequests;
+        }
+        if wants_close {
+            break CloseReason::ConnectionClose;
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    println!("{}", stats.summary(addr, reason));
+    // the peer already tore the connection down, a shutdown would only fail noisily
+    if !matches!(reason, CloseReason::ClientAborted) {
+        let _context = log_context::enter(log_context::RequestId {
+            connection: connection_id,
+            request: stats.requests.max(1),
+        });
+        stream
+            .shutdown(std::net::Shutdown::Both)
+            .unwrap_or_else(|e| {
+                if e.kind() != io::ErrorKind::NotConnected {
+                    log_error!("Failed to shutdown stream: {}", e);
+                }
+            });
+    }
+}
+
+// Answering a keep-alive exchange of a directory listing and a file takes just
+// under 32 KiB of a connection thread's stack in a debug build and under 16 KiB
+// in a release one, io::copy's 8 KiB buffer being the largest frame. Twice the
+// debug figure leaves headroom for the OS and for error formatting, anything
+// smaller is rejected instead of risking a stack overflow at runtime.
+const MIN_THREAD_STACK_SIZE: usize = 64 * 1024;
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+// nothing served here takes a body, it is only read to keep the connection usable
+const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+const DEFAULT_AUTOINDEX_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_INTERNAL_PREFIX: &str = "/__";
+const DEFAULT_INDEX_REFRESH_SECS: u64 = 60;
+const DEFAULT_LOG_KEEP: usize = 5;
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 600;
+const NOT_FOUND_PAGE: &str = "404.html";
+const DEFAULT_MAINTENANCE_RETRY_AFTER_SECS: u64 = 300;
+
+const USAGE: &str = "usage: http_server [options] [port] [directory]
+the directory may also be a single file, which is then served for /
+the port may be left out when the PORT environment variable holds it, port 0
+picks a free one, which is the one reported as listening
+options:
+  --autoindex                  list directories that have no index.html
+  --autoindex-max-entries <n>  entries shown before a listing is truncated
+                               (default 10000)
+  --dir-redirect-code <code>   status of the redirects from a directory to its
+                               index and of --trailing-slash redirects: 301,
+                               302, 307 or 308
+                               (default 301, also --index-redirect-code)
+  --trailing-slash <policy>    a trailing slash on a file or its absence on a
+                               directory is: redirect (default) to the right
+                               form, strict (404), or ignore (served as is)
+  --probe-extensions <list>    comma separated extensions tried in order for a
+                               missing path without one, /about being served
+                               from about.html with html
+  --allow-trace                answer TRACE with an echo instead of 405
+                               (also accepted as --enable-trace)
+  --allow-methods <list>       comma separated methods to serve, any other is
+                               answered 405 (default: all that are supported)
+  --deny-methods <list>        comma separated methods answered 405
+  --internal-prefix <prefix>   path prefix reserved for built-in endpoints such
+                               as <prefix>health and <prefix>metrics (default
+                               /__)
+  --no-internal-endpoints      serve the reserved prefix from disk like any path
+  --spa                        with a single file, serve it for every path
+  --no-keep-alive              close every connection after its first response
+  --timing-header              report the time spent building each response head,
+                               before the body is read or sent, in Server-Timing
+                               (`head`) and X-Response-Head-Time headers
+  --debug-headers              report in X-Requests-Served and
+                               X-Requests-Remaining how many requests the
+                               connection has had and has left
+  --verbose-errors             show the underlying error and path in 500 pages,
+                               for local debugging only as it reveals paths
+  --strip-bom                  leave a leading UTF-8 byte order mark out of text
+                               files (by default they are sent byte for byte)
+  --save-data                  answer requests with Save-Data: on with the
+                               lighter name.sd.ext next to name.ext, if any
+  --inject-head <file>         insert <file> before </head> in served HTML pages
+  --inject-body <file>         insert <file> before </body> in served HTML pages
+  --mmap                       send file bodies from memory mappings (needs the
+                               mmap feature, files must be replaced by rename,
+                               never truncated in place while being served)
+  --maintenance                answer every request with a 503 (the built-in
+                               endpoints keep working)
+  --maintenance-file <file>    be in maintenance while <file> exists
+  --maintenance-retry-after <secs>
+                               Retry-After sent in maintenance (default 300)
+  --maintenance-page <file>    HTML page sent in maintenance
+  --no-404-page                answer missing paths with the built-in page even
+                               when the document root has a 404.html
+  --once                       serve a single connection until it closes, then
+                               exit 0 (also on a shutdown signal meanwhile)
+  --strict                     treat document root warnings as fatal
+  --default-vhost <dir>        site served to hosts without their own directory
+  --root <dir>                 look up files missing from the document root (or
+                               host directory) in <dir>, repeatable, tried in order
+  --internal-root <dir>        files only served through X-Accel-Redirect
+  --trust-proxy                honour the headers of a proxy in front: serve
+                               <path> from --internal-root for X-Accel-Redirect:
+                               <path>, and redirect to --canonical-host over
+                               https for X-Forwarded-Proto: https. Only safe when
+                               every client is a proxy that strips these headers
+                               from requests it has not made itself
+  --acme-challenge-dir <dir>   serve /.well-known/acme-challenge/ from <dir>
+  --charset <name>             charset of text files (default utf-8)
+  --charset <ext>=<name>       charset of text files with extension <ext>,
+                               repeatable
+  --index-tree                 index the document root at startup, answering 404
+                               for paths missing from it without disk access
+  --index-refresh <secs>       how often the index is rebuilt (default 60)
+  --no-follow-symlinks         refuse paths through symbolic links (by default
+                               links are followed if they stay inside the root)
+  --preload-manifest <file>    JSON mapping page paths to resources sent as
+                               Link: rel=preload headers with those pages
+  --preload <path>;as=<type>   send a Link: rel=preload header for <path> with
+                               every HTML page, repeatable
+  --early-hints                also send the preload links in a 103 response
+  --webdav-readonly            answer PROPFIND (Depth 0 and 1) so WebDAV clients
+                               can browse the tree, listings are capped like
+                               --autoindex-max-entries
+  --route <path>=<type>:<body> answer <path> with a fixed 200 of content type
+                               <type>, a body of @<file> is read at startup,
+                               repeatable
+  --redirects <file>           redirect rules, one `from to [code]` per line, a
+                               trailing * in from matches everything below it
+  --list-mime                  print the content type of every known extension
+                               and the --type rules, then exit without serving
+  --type <path>=<type>         serve the file at <path> as content type <type>,
+                               whatever its extension, repeatable
+  --strip-prefix <prefix>      serve only paths under <prefix>, with it removed
+  --mount <prefix>=<dir>       serve paths under <prefix> from <dir>, repeatable,
+                               the longest matching prefix wins
+  --cache-profile <name>       Cache-Control defaults: none, static, aggressive
+  --immutable-pattern <glob>   files whose name matches <glob> (`*` and `?`) are
+                               cached forever and validated by name, repeatable
+  --cors-origin <origin>       let scripts from <origin> read responses, * for
+                               any origin, repeatable
+  --cors-preflight-max-age <secs>
+                               how long browsers may cache a preflight (default
+                               600)
+  --cors-credentials           let those scripts send cookies and credentials,
+                               echoing their origin even when * is allowed
+  --error-format <format>      body of error responses: html (default), json, or
+                               auto for JSON when Accept prefers it over HTML
+  --log-format <format>        print an access log line per request, format is
+                               common, combined or json
+  --access-log <file>          append the access log to <file> instead of stdout
+                               (format common unless --log-format is given)
+  --log-max-size <bytes>       rotate the access log file before it grows past
+                               <bytes> (default: never)
+  --log-keep <n>               rotated access logs kept, <file>.1 being the
+                               newest (default 5)
+  --access-log-exclude <prefix>
+                               leave requests for paths starting with <prefix>
+                               out of the access log, repeatable
+  --max-body-size <bytes>      largest request body accepted (default 1048576)
+  --max-connections-per-ip <n> connections one client may hold open, further
+                               ones are answered 503 and closed (default: any)
+  --global-rate-limit <rps>    requests per second accepted across all clients,
+                               further ones are answered 503 and closed
+                               (alias --max-request-rate, default: any)
+  --allowed-hosts <list>       comma separated hosts to answer, .example.com
+                               matches every subdomain (default: any host)
+  --canonical-host <host>      redirect requests for any other host name to the
+                               same URL on <host> with a 301, e.g. www. to apex
+  --strict-crlf                refuse requests whose request line or headers end
+                               in a bare LF instead of CRLF
+  --thread-stack-size <bytes>  stack size of connection threads (min 65536)
+  --keep-alive-timeout <ms>    how long an idle connection waits for its next
+                               request (default 5000)
+  --tcp-keepalive <secs>       have the OS probe connections idle for <secs> and
+                               drop those whose peer is gone (default 0, off)
+  --request-timeout <ms>       how long reading a request that has started may
+                               stall (default 1000)
+  --shutdown-timeout <secs>    how long to wait for requests on shutdown (default 10)";
+
+struct ProgArgs {
+    port: u16,
+    directory: PathBuf,
+    autoindex: bool,
+    autoindex_max_entries: usize,
+    // one of the codes Status::redirect knows
+    dir_redirect_code: u16,
+    trailing_slash: TrailingSlash,
+    // without the leading dot, tried in order for missing paths without one
+    probe_extensions: Vec<String>,
+    allow_trace: bool,
+    // upper case, None allows every method
+    allow_methods: Option<Vec<String>>,
+    deny_methods: Vec<String>,
+    strict: bool,
+    spa: bool,
+    no_keep_alive: bool,
+    timing_header: bool,
+    debug_headers: bool,
+    verbose_errors: bool,
+    strip_bom: bool,
+    // serve `photo.sd.jpg` for `photo.jpg` to clients sending Save-Data: on
+    save_data: bool,
+    inject: inject::Inject,
+    // always false without the mmap feature
+    mmap: bool,
+    maintenance: maintenance::Maintenance,
+    // a 404.html in the document root replaces the built-in 404 page
+    not_found_page: bool,
+    once: bool,
+    internal_router: Option<internal::InternalRouter>,
+    // exact paths answered before the filesystem, bodies loaded in main
+    routes: Vec<static_routes::StaticRoute>,
+    redirects_file: Option<PathBuf>,
+    // loaded in main from redirects_file
+    redirects: Option<redirects::Redirects>,
+    // set at startup when the directory argument turns out to be a file
+    single_file: bool,
+    vhosts: bool,
+    default_vhost: Option<PathBuf>,
+    // overlaid below every site root, in command line order
+    fallback_roots: Vec<PathBuf>,
+    acme_challenge_dir: Option<PathBuf>,
+    // only reachable through X-Accel-Redirect, never by its own URLs
+    internal_root: Option<PathBuf>,
+    trust_proxy: bool,
+    // without a trailing slash, "/" itself is stored as None
+    strip_prefix: Option<String>,
+    // (url prefix without trailing slash, directory), longest prefix first
+    mounts: Vec<(String, PathBuf)>,
+    charset: String,
+    // (extension, charset), looked up before the default
+    charset_overrides: Vec<(String, String)>,
+    list_mime: bool,
+    // (normalized request path, content type), ahead of the extension guess
+    type_overrides: Vec<(String, String)>,
+    // built in main once every root has been resolved
+    tree_index: Option<Arc<tree_index::TreeIndex>>,
+    follow_symlinks: bool,
+    preload_manifest: Option<PathBuf>,
+    // loaded in main from preload_manifest
+    preload: Option<preload::PreloadManifest>,
+    // from --preload, sent with every HTML page ahead of the manifest's
+    preload_links: Vec<String>,
+    early_hints: bool,
+    webdav_readonly: bool,
+    index_tree: bool,
+    index_refresh: Duration,
+    cache_profile: CacheProfile,
+    // file name globs, see is_immutable
+    immutable_patterns: Vec<String>,
+    error_format: ErrorFormat,
+    // built from --cors-origin and --cors-preflight-max-age
+    cors: Option<cors::Cors>,
+    allowed_hosts: Option<Vec<String>>,
+    canonical_host: Option<String>,
+    strict_crlf: bool,
+    max_body_size: u64,
+    client_limit: Option<client_limit::ClientLimit>,
+    rate_limit: Option<rate_limit::RateLimit>,
+    log_format: Option<access_log::LogFormat>,
+    access_log_file: Option<PathBuf>,
+    log_max_size: Option<u64>,
+    log_keep: usize,
+    // path prefixes whose requests are not logged
+    access_log_exclude: Vec<String>,
+    // opened in main from access_log_file
+    access_log: Option<access_log::LogFile>,
+    thread_stack_size: Option<usize>,
+    keep_alive_timeout: Duration,
+    // SO_KEEPALIVE idle time of accepted sockets, None leaves them without
+    tcp_keepalive: Option<Duration>,
+    request_timeout: Duration,
+    shutdown_timeout: Duration,
+}
+// the port from $PORT, which is reported when set but not a port
+fn env_port() -> Option<u16> {
+    let value = env::var("PORT").ok()?;
+    let port = value.trim().parse().ok();
+    if port.is_none() {
+        eprintln!("Invalid PORT environment variable: {:?}", value);
+    }
+    port
+}
+
+// methods are case-sensitive, but nobody means `get` to be a different method
+fn parse_methods(list: &str) -> Option<Vec<String>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|method| !method.is_empty())
+        .map(|method| {
+            method
+                .bytes()
+                .all(is_token_char)
+                .then(|| method.to_ascii_uppercase())
+        })
+        .collect()
+}
+
+fn parse_args(mut args: Args) -> Option<ProgArgs> {
+    let _name = args.next()?;
+    let mut positional = vec![];
+    let mut autoindex = false;
+    let mut autoindex_max_entries = DEFAULT_AUTOINDEX_MAX_ENTRIES;
+    let mut dir_redirect_code = 301;
+    let mut trailing_slash = TrailingSlash::Redirect;
+    let mut probe_extensions = vec![];
+    let mut allow_trace = false;
+    let mut allow_methods = None;
+    let mut deny_methods = vec![];
+    let mut strict = false;
+    let mut spa = false;
+    let mut no_keep_alive = false;
+    let mut timing_header = false;
+    let mut debug_headers = false;
+    let mut verbose_errors = false;
+    let mut strip_bom = false;
+    let mut save_data = false;
+    let mut inject_head = None;
+    let mut inject_body = None;
+    #[cfg_attr(not(feature = "mmap"), allow(unused_mut))]
+    let mut mmap = false;
+    let mut not_found_page = true;
+    let mut maintenance = false;
+    let mut maintenance_file = None;
+    let mut maintenance_retry_after = DEFAULT_MAINTENANCE_RETRY_AFTER_SECS;
+    let mut maintenance_page = None;
+    let mut once = false;
+    let mut internal_endpoints = true;
+    let mut internal_prefix = DEFAULT_INTERNAL_PREFIX.to_string();
+    let mut cache_profile = CacheProfile::None;
+    let mut immutable_patterns = vec![];
+    let mut error_format = ErrorFormat::Html;
+    let mut cors_origins = vec![];
+    let mut cors_max_age = DEFAULT_CORS_MAX_AGE_SECS;
+    let mut cors_credentials = false;
+    let mut allowed_hosts = None;
+    let mut canonical_host = None;
+    let mut strict_crlf = false;
+    let mut max_body_size = DEFAULT_MAX_BODY_SIZE;
+    let mut client_limit = None;
+    let mut rate_limit = None;
+    let mut routes = vec![];
+    let mut redirects_file = None;
+    let mut log_format = None;
+    let mut access_log_file = None;
+    let mut log_max_size = None;
+    let mut log_keep = DEFAULT_LOG_KEEP;
+    let mut access_log_exclude = vec![];
+    let mut thread_stack_size = None;
+    let mut default_vhost = None;
+    let mut fallback_roots = vec![];
+    let mut acme_challenge_dir = None;
+    let mut internal_root = None;
+    let mut trust_proxy = false;
+    let mut strip_prefix = None;
+    let mut mounts = vec![];
+    let mut charset = "utf-8".to_string();
+    let mut charset_overrides = vec![];
+    let mut list_mime = false;
+    let mut type_overrides = vec![];
+    let mut index_tree = false;
+    let mut follow_symlinks = true;
+    let mut preload_manifest = None;
+    let mut preload_links = vec![];
+    let mut early_hints = false;
+    let mut webdav_readonly = false;
+    let mut index_refresh = Duration::from_secs(DEFAULT_INDEX_REFRESH_SECS);
+    let mut keep_alive_timeout = Duration::from_millis(DEFAULT_KEEP_ALIVE_TIMEOUT_MS);
+    let mut tcp_keepalive = None;
+    let mut request_timeout = Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS);
+    let mut shutdown_timeout = Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--autoindex" => autoindex = true,
+            "--autoindex-max-entries" => autoindex_max_entries = args.next()?.parse().ok()?,
+            // a temporary code keeps browsers from caching the redirect while developing
+            "--dir-redirect-code" | "--index-redirect-code" => {
+                let code = args.next()?.parse().ok()?;
+                Status::redirect(code, String::new())?;
+                dir_redirect_code = code;
+            }
+            "--trailing-slash" => {
+                trailing_slash = match args.next()?.as_str() {
+                    "redirect" => TrailingSlash::Redirect,
+                    "strict" => TrailingSlash::Strict,
+                    "ignore" | "serve" => TrailingSlash::Ignore,
+                    _ => return None,
+                }
+            }
+            "--probe-extensions" => {
+                probe_extensions = args
+                    .next()?
+                    .split(',')
+                    .map(|ext| ext.trim().trim_start_matches('.'))
+                    .filter(|ext| !ext.is_empty())
+                    .map(|ext| {
+                        // appended to a path segment, it must stay within it
+                        ext.bytes()
+                            .all(|b| {
+                                b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_'
+                            })
+                            .then(|| ext.to_string())
+                    })
+                    .collect::<Option<_>>()?;
+            }
+            "--allow-trace" | "--enable-trace" => allow_trace = true,
+            "--allow-methods" => allow_methods = Some(parse_methods(&args.next()?)?),
+            "--deny-methods" => deny_methods = parse_methods(&args.next()?)?,
+            "--strict" => strict = true,
+            "--spa" => spa = true,
+            "--no-keep-alive" => no_keep_alive = true,
+            "--timing-header" => timing_header = true,
+            "--debug-headers" => debug_headers = true,
+            "--verbose-errors" => verbose_errors = true,
+            "--strip-bom" => strip_bom = true,
+            "--save-data" => save_data = true,
+            "--inject-head" => inject_head = Some(PathBuf::from(args.next()?)),
+            "--inject-body" => inject_body = Some(PathBuf::from(args.next()?)),
+            #[cfg(feature = "mmap")]
+            "--mmap" => mmap = true,
+            "--no-404-page" => not_found_page = false,
+            "--maintenance" => maintenance = true,
+            "--maintenance-file" => maintenance_file = Some(PathBuf::from(args.next()?)),
+            "--maintenance-retry-after" => maintenance_retry_after = args.next()?.parse().ok()?,
+            "--maintenance-page" => maintenance_page = Some(PathBuf::from(args.next()?)),
+            "--once" => once = true,
+            "--no-internal-endpoints" => internal_endpoints = false,
+            "--internal-prefix" => {
+                let prefix = args.next()?;
+                if !prefix.starts_with('/') {
+                    return None;
+                }
+                internal_prefix = prefix;
+            }
+            "--log-format" => {
+                log_format = Some(match args.next()?.as_str() {
+                    "common" | "clf" => access_log::LogFormat::Common,
+                    "combined" => access_log::LogFormat::Combined,
+                    "json" => access_log::LogFormat::Json,
+                    _ => return None,
+                })
+            }
+            "--access-log" => access_log_file = Some(PathBuf::from(args.next()?)),
+            "--log-max-size" => {
+                log_max_size = Some(args.next()?.parse().ok().filter(|max| *max > 0)?)
+            }
+            "--log-keep" => log_keep = args.next()?.parse().ok()?,
+            "--access-log-exclude" => access_log_exclude.push(args.next()?),
+            "--max-body-size" => max_body_size = args.next()?.parse().ok()?,
+            "--route" => routes.push(static_routes::StaticRoute::parse(&args.next()?)?),
+            "--redirects" => redirects_file = Some(PathBuf::from(args.next()?)),
+            "--max-connections-per-ip" => {
+                let max = args.next()?.parse().ok().filter(|max| *max > 0)?;
+                client_limit = Some(client_limit::ClientLimit::new(max));
+            }
+            "--global-rate-limit" | "--max-request-rate" => {
+                let rps = args.next()?.parse().ok().filter(|rps| *rps > 0)?;
+                rate_limit = Some(rate_limit::RateLimit::new(rps));
+            }
+            "--allowed-hosts" => {
+                allowed_hosts = Some(
+                    args.next()?
+                        .split(',')
+                        .map(|host| match host.trim().strip_prefix('.') {
+                            Some(suffix) => format!(".{}", normalize_host(suffix)),
+                            None => normalize_host(host.trim()),
+                        })
+                        .filter(|host| !host.is_empty())
+                        .collect(),
+                );
+            }
+            "--canonical-host" => {
+                let host = args.next()?.trim_end_matches('.').to_ascii_lowercase();
+                // it becomes the authority of a Location
+                if host.is_empty()
+                    || !host
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"-.[]:".contains(&b))
+                {
+                    return None;
+                }
+                canonical_host = Some(host);
+            }
+            "--strict-crlf" => strict_crlf = true,
+            "--cache-profile" => {
+                cache_profile = match args.next()?.as_str() {
+                    "none" => CacheProfile::None,
+                    "static" => CacheProfile::Static,
+                    "aggressive" => CacheProfile::Aggressive,
+                    _ => return None,
+                }
+            }
+            "--immutable-pattern" => immutable_patterns.push(args.next()?),
+            "--cors-origin" => {
+                let origin = args.next()?;
+                // it is echoed in a header value
+                if !is_valid_header("Access-Control-Allow-Origin", &origin) {
+                    return None;
+                }
+                cors_origins.push(origin);
+            }
+            "--cors-preflight-max-age" => cors_max_age = args.next()?.parse().ok()?,
+            "--cors-credentials" => cors_credentials = true,
+            "--error-format" => {
+                error_format = match args.next()?.as_str() {
+                    "html" => ErrorFormat::Html,
+                    "json" => ErrorFormat::Json,
+                    "auto" => ErrorFormat::Auto,
+                    _ => return None,
+                }
+            }
+            "--thread-stack-size" => {
+                let size: usize = args.next()?.parse().ok()?;
+                if size < MIN_THREAD_STACK_SIZE {
+                    eprintln!(
+                        "--thread-stack-size must be at least {} bytes",
+                        MIN_THREAD_STACK_SIZE
+                    );
+                    return None;
+                }
+                thread_stack_size = Some(size);
+            }
+            "--default-vhost" => default_vhost = Some(PathBuf::from(args.next()?)),
+            "--root" => fallback_roots.push(PathBuf::from(args.next()?)),
+            "--acme-challenge-dir" => acme_challenge_dir = Some(PathBuf::from(args.next()?)),
+            "--internal-root" => internal_root = Some(PathBuf::from(args.next()?)),
+            "--trust-proxy" => trust_proxy = true,
+            "--mount" => {
+                let mount = args.next()?;
+                let (prefix, dir) = mount.split_once('=')?;
+                if !prefix.starts_with('/') || dir.is_empty() {
+                    return None;
+                }
+                mounts.push((prefix.trim_end_matches('/').to_string(), PathBuf::from(dir)));
+            }
+            "--index-tree" => index_tree = true,
+            "--no-follow-symlinks" => follow_symlinks = false,
+            "--preload-manifest" => preload_manifest = Some(PathBuf::from(args.next()?)),
+            "--preload" => preload_links.push(preload::parse_option(&args.next()?)?),
+            "--early-hints" => early_hints = true,
+            "--webdav-readonly" => webdav_readonly = true,
+            "--index-refresh" => {
+                let secs = args.next()?.parse().ok().filter(|secs| *secs > 0)?;
+                index_refresh = Duration::from_secs(secs);
+            }
+            "--charset" => {
+                let value = args.next()?;
+                let (ext, name) = match value.split_once('=') {
+                    Some((ext, name)) => (Some(ext.trim_start_matches('.')), name),
+                    None => (None, value.as_str()),
+                };
+                // it ends up in a header value
+                if name.is_empty() || !name.bytes().all(is_token_char) {
+                    return None;
+                }
+                match ext {
+                    Some(ext) => charset_overrides.push((ext.to_string(), name.to_string())),
+                    None => charset = name.to_string(),
+                }
+            }
+            "--list-mime" => list_mime = true,
+            "--type" => {
+                let value = args.next()?;
+                let (path, content_type) = value.split_once('=')?;
+                let content_type = content_type.trim();
+                if !path.starts_with('/') || !is_valid_header("Content-Type", content_type) {
+                    return None;
+                }
+                type_overrides.push((normalize_url_path(path)?, content_type.to_string()));
+            }
+            "--strip-prefix" => {
+                let prefix = args.next()?;
+                if !prefix.starts_with('/') {
+                    return None;
+                }
+                strip_prefix = Some(prefix.trim_end_matches('/').to_string())
+                    .filter(|prefix| !prefix.is_empty());
+            }
+            // a zero read timeout is rejected by std, and would never expire in tokio
+            "--keep-alive-timeout" => {
+                let ms = args.next()?.parse().ok().filter(|ms| *ms > 0)?;
+                keep_alive_timeout = Duration::from_millis(ms);
+            }
+            "--tcp-keepalive" => {
+                let secs = args.next()?.parse().ok()?;
+                tcp_keepalive = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "--request-timeout" => {
+                let ms = args.next()?.parse().ok().filter(|ms| *ms > 0)?;
+                request_timeout = Duration::from_millis(ms);
+            }
+            "--shutdown-timeout" => {
+                shutdown_timeout = Duration::from_secs(args.next()?.parse().ok()?);
+            }
+            flag if flag.starts_with("--") => return None,
+            _ => positional.push(arg),
+        }
+    }
+    // stable, mounts with the same prefix keep their command line order
+    mounts.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    let (port, directory) = match positional.as_slice() {
+        // nothing is served, there is no need to say where from
+        [] if list_mime => (0, PathBuf::new()),
+        [port, directory] => (port.parse().ok()?, directory.parse().ok()?),
+        // platforms such as Heroku hand out the port through the environment
+        [directory] => (env_port()?, directory.parse().ok()?),
+        _ => return None,
+    };
+    Some(ProgArgs {
+        port,
+        directory,
+        autoindex,
+        autoindex_max_entries,
+        dir_redirect_code,
+        trailing_slash,
+        probe_extensions,
+        allow_trace,
+        allow_methods,
+        deny_methods,
+        strict,
+        spa,
+        no_keep_alive,
+        timing_header,
+        debug_headers,
+        verbose_errors,
+        strip_bom,
+        save_data,
+        inject: inject::Inject::new(inject_head, inject_body),
+        mmap,
+        maintenance: maintenance::Maintenance::new(
+            maintenance,
+            maintenance_file,
+            maintenance_retry_after,
+            maintenance_page,
+        ),
+        not_found_page,
+        once,
+        internal_router: internal_endpoints.then(|| internal::InternalRouter::new(internal_prefix)),
+        single_file: false,
+        cache_profile,
+        immutable_patterns,
+        error_format,
+        cors: (!cors_origins.is_empty())
+            .then(|| cors::Cors::new(cors_origins, cors_max_age, cors_credentials)),
+        allowed_hosts,
+        canonical_host,
+        strict_crlf,
+        max_body_size,
+        client_limit,
+        rate_limit,
+        routes,
+        redirects_file,
+        redirects: None,
+        // a log file without a format gets the common one
+        log_format: log_format.or(access_log_file
+            .as_ref()
+            .map(|_| access_log::LogFormat::Common)),
+        access_log_file,
+        log_max_size,
+        log_keep,
+        access_log_exclude,
+        access_log: None,
+        vhosts: env::var("HOST_NOT_DEFINED").unwrap_or_default() != "1",
+        thread_stack_size,
+        default_vhost,
+        fallback_roots,
+        acme_challenge_dir,
+        internal_root,
+        trust_proxy,
+        strip_prefix,
+        mounts,
+        charset,
+        charset_overrides,
+        list_mime,
+        type_overrides,
+        tree_index: None,
+        follow_symlinks,
+        preload_manifest,
+        preload: None,
+        preload_links,
+        early_hints,
+        webdav_readonly,
+        index_tree,
+        index_refresh,
+        keep_alive_timeout,
+        tcp_keepalive,
+        request_timeout,
+        shutdown_timeout,
+    })
+}
+/// Checks the document root once at startup and resolves it to an absolute path,
+/// so a later change of the working directory cannot move it. Also returns the
+/// host directories found in vhost mode. Suspicious but servable layouts are
+/// warnings, unless `strict` is set.
+// A directory found to be missing or unlistable at startup would otherwise only
+// show up as a 403/404 on the first request that reaches it.
+fn servable_dir(p: &Path) -> Result<PathBuf, String> {
+    let canonical = p.canonicalize().map_err(|e| e.to_string())?;
+    if !canonical.is_dir() {
+        return Err("not a directory".to_string());
+    }
+    fs::read_dir(&canonical).map_err(|e| format!("not readable: {}", e))?;
+    Ok(canonical)
+}
+
+fn validate_document_root(args: &ProgArgs) -> Result<(PathBuf, Vec<String>), String> {
+    let canonical = args.directory.canonicalize().map_err(|e| e.to_string())?;
+    if canonical.is_file() {
+        // a single file is served as the whole site
+        fs::File::open(&canonical).map_err(|e| format!("not readable: {}", e))?;
+        return Ok((canonical, vec![]));
+    }
+    if !canonical.is_dir() {
+        return Err("not a directory or regular file".to_string());
+    }
+    let entries: Vec<fs::DirEntry> = fs::read_dir(&canonical)
+        .map_err(|e| format!("not readable: {}", e))?
+        .filter_map(|entry| entry.ok())
+        .collect();
+
+    let mut problems = vec![];
+    if entries.is_empty() {
+        problems.push("document root is empty".to_string());
+    }
+    let mut hosts = vec![];
+    if args.vhosts {
+        hosts = entries
+            .iter()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        hosts.sort();
+        for host in &hosts {
+            if let Err(e) = fs::read_dir(canonical.join(host)) {
+                problems.push(format!("host directory {} is not readable: {}", host, e));
+            }
+        }
+        if hosts.is_empty() && !entries.is_empty() {
+            problems.push(
+                "no host directories found (set HOST_NOT_DEFINED=1 to serve the root itself)"
+                    .to_string(),
+            );
+        }
+        let explicit_hosts = args.allowed_hosts.iter().flatten();
+        for host in explicit_hosts.filter(|host| !host.starts_with('.')) {
+            if !hosts.iter().any(|dir| normalize_host(dir) == *host) {
+                problems.push(format!("allowed host {} has no directory", host));
+            }
+        }
+    }
+
+    for problem in &problems {
+        eprintln!("warning: {}", problem);
+    }
+    if args.strict && !problems.is_empty() {
+        return Err("refusing to start in strict mode".to_string());
+    }
+    Ok((canonical, hosts))
+}
+
+fn main() {
+    let mut args = match parse_args(env::args()) {
+        Some(x) => x,
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+    if args.list_mime {
+        print_media_types(&args);
+        return;
+    }
+    let hosts = match validate_document_root(&args) {
+        Ok((dir, hosts)) => {
+            args.single_file = dir.is_file();
+            args.directory = dir;
+            hosts
+        }
+        Err(e) => {
+            eprintln!("Invalid document root {}: {}", args.directory.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let optional_dirs = [
+        ("default vhost", &mut args.default_vhost),
+        ("ACME challenge directory", &mut args.acme_challenge_dir),
+        ("internal root", &mut args.internal_root),
+    ];
+    let mount_dirs = args.mounts.iter_mut().map(|(_, dir)| ("mount", dir));
+    let fallback_dirs = args.fallback_roots.iter_mut().map(|dir| ("root", dir));
+    for (what, path) in optional_dirs
+        .into_iter()
+        .filter_map(|(what, dir)| Some((what, dir.as_mut()?)))
+        .chain(mount_dirs)
+        .chain(fallback_dirs)
+    {
+        match servable_dir(path) {
+            Ok(canonical) => *path = canonical,
+            Err(e) => {
+                eprintln!("Invalid {} {}: {}", what, path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    VERBOSE_ERRORS.store(args.verbose_errors, Ordering::Relaxed);
+    if let Err(e) = args.maintenance.load() {
+        eprintln!("Invalid maintenance page: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = args.inject.load() {
+        eprintln!("Invalid injected snippet: {}", e);
+        std::process::exit(1);
+    }
+    for route in &mut args.routes {
+        if let Err(e) = route.load() {
+            eprintln!("Invalid route {}: {}", route.path(), e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(p) = &args.access_log_file {
+        match access_log::LogFile::open(p.clone(), args.log_max_size, args.log_keep) {
+            Ok(file) => args.access_log = Some(file),
+            Err(e) => {
+                eprintln!("Cannot open access log {}: {}", p.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(p) = &args.redirects_file {
+        match redirects::Redirects::load(p) {
+            Ok(redirects) => {
+                println!("loaded {} redirects from: {}", redirects.len(), p.display());
+                args.redirects = Some(redirects);
+            }
+            Err(e) => {
+                eprintln!("Invalid redirects file {}: {}", p.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(p) = &args.preload_manifest {
+        match preload::PreloadManifest::load(p) {
+            Ok(manifest) => args.preload = Some(manifest),
+            Err(e) => {
+                eprintln!("Invalid preload manifest {}: {}", p.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.index_tree && !args.single_file {
+        let mut roots = vec![args.directory.clone()];
+        roots.extend(args.default_vhost.clone());
+        roots.extend(args.mounts.iter().map(|(_, dir)| dir.clone()));
+        roots.extend(args.fallback_roots.clone());
+        let index = Arc::new(tree_index::TreeIndex::build(roots));
+        Arc::clone(&index).refresh_every(args.index_refresh);
+        args.tree_index = Some(index);
+    }
+    let args = Arc::new(args);
 
     let saddr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 1, 1), args.port);
-    println!("listening on address: http://{}", saddr);
     let listener = match std::net::TcpListener::bind(saddr) {
         Ok(l) => l,
         Err(e) => {
@@ -417,15 +3616,545 @@ fn main() {
             std::process::exit(1);
         }
     };
+    // the port actually bound when 0 was asked for
+    let saddr = match listener.local_addr() {
+        Ok(SocketAddr::V4(bound)) => bound,
+        _ => saddr,
+    };
+    // before the address is announced, so that any signal from then on is graceful;
+    // std retries accept() on EINTR, the handler ends it with a connection instead
+    shutdown::install_handler(saddr);
+    println!("listening on address: http://{}", saddr);
+    if args.single_file {
+        println!("serving single file: {}", args.directory.display());
+    } else {
+        println!("serving files from: {}", args.directory.display());
+    }
+    for host in &hosts {
+        println!("serving host: {}", host);
+    }
+    for (prefix, dir) in &args.mounts {
+        println!("serving {}/ from: {}", prefix, dir.display());
+    }
+    if let Some(dir) = args.default_vhost.as_ref().filter(|_| args.vhosts) {
+        println!("serving other hosts from: {}", dir.display());
+    }
+    for dir in &args.fallback_roots {
+        println!("falling back to: {}", dir.display());
+    }
+
+    #[cfg(feature = "async")]
+    async_server::run(args, listener, saddr);
+    #[cfg(not(feature = "async"))]
+    serve_blocking(args, listener, saddr);
+}
+
+#[cfg(not(feature = "async"))]
+fn serve_blocking(args: Arc<ProgArgs>, listener: std::net::TcpListener, saddr: SocketAddrV4) {
+    let tracker = Arc::new(shutdown::ConnectionTracker::default());
 
-    for stream_result in listener.incoming() {
-        match stream_result {
-            Ok(stream) => {
-                handle_connection(&args.directory, stream, saddr);
+    loop {
+        let accepted = listener.accept();
+        if shutdown::requested() {
+            break;
+        }
+        match accepted {
+            Ok((stream, _)) => {
+                metrics::connection_accepted();
+                if args.once {
+                    // on this thread, keep-alive requests are all answered before exiting
+                    handle_connection(&args, &tracker, stream, saddr);
+                    break;
+                }
+                let mut builder = std::thread::Builder::new();
+                if let Some(size) = args.thread_stack_size {
+                    builder = builder.stack_size(size);
+                }
+                let args = Arc::clone(&args);
+                let tracker = Arc::clone(&tracker);
+                if let Err(e) =
+                    builder.spawn(move || handle_connection(&args, &tracker, stream, saddr))
+                {
+                    eprintln!("Failed to spawn connection thread: {}", e);
+                }
             }
             Err(e) => {
                 eprintln!("Error accepting connection: {}", e);
+                metrics::accept_failed();
+            }
+        }
+    }
+
+    drop(listener);
+    shutdown::finish(&tracker, args.shutdown_timeout);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    // the response as sent, with its Date line left out
+    fn written(response: Response) -> String {
+        let mut wire = Vec::new();
+        response.write_to(&mut wire).unwrap();
+        String::from_utf8(wire)
+            .unwrap()
+            .split_inclusive("\r\n")
+            .filter(|line| !line.starts_with("Date: "))
+            .collect()
+    }
+
+    // takes at most `step` bytes a write, after `stalls` timed-out writes each time
+    #[cfg(not(feature = "async"))]
+    struct Throttled {
+        received: Vec<u8>,
+        step: usize,
+        stalls: u32,
+        stalled: u32,
+    }
+
+    #[cfg(not(feature = "async"))]
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.stalled < self.stalls {
+                self.stalled += 1;
+                let kind = match self.stalled % 3 {
+                    0 => io::ErrorKind::Interrupted,
+                    1 => io::ErrorKind::WouldBlock,
+                    _ => io::ErrorKind::TimedOut,
+                };
+                return Err(kind.into());
             }
+            self.stalled = 0;
+            let n = buf.len().min(self.step);
+            self.received.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn throttled_writer_gets_the_whole_response() {
+        let body: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let response = || {
+            build_http_response(
+                Status::Success,
+                "application/octet-stream",
+                Cow::Owned(body.clone()),
+            )
+        };
+        let mut expected = Vec::new();
+        response().write_to(&mut expected).unwrap();
+        let mut writer = Retrying {
+            inner: Throttled {
+                received: vec![],
+                step: 7,
+                stalls: 5,
+                stalled: 0,
+            },
+        };
+        response().write_to(&mut writer).unwrap();
+        let received = &writer.inner.received;
+        // only the Date lines may differ, by a second
+        assert_eq!(received.len(), expected.len());
+        assert!(received.ends_with(&body));
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn stalled_writer_gives_up() {
+        let mut writer = Retrying {
+            inner: Throttled {
+                received: vec![],
+                step: 1,
+                stalls: u32::MAX,
+                stalled: 0,
+            },
+        };
+        let err = writer.write_all(b"response").unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        assert!(writer.inner.received.is_empty());
+    }
+
+    #[test]
+    fn json_preferred() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("APPLICATION/JSON")));
+        assert!(prefers_json(Some("text/html;q=0.9, application/json")));
+        assert!(prefers_json(Some("application/*, text/html;q=0.5")));
+        assert!(prefers_json(Some("application/json;q=0.5, */*;q=0.1")));
+    }
+
+    #[test]
+    fn html_preferred() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("")));
+        assert!(!prefers_json(Some("text/html")));
+        assert!(!prefers_json(Some("*/*")));
+        // ties go to HTML
+        assert!(!prefers_json(Some("application/json, text/html")));
+        // the most specific range decides, not the highest weight
+        assert!(!prefers_json(Some("application/json;q=0, */*")));
+        // an entry with a malformed weight is left out
+        assert!(!prefers_json(Some(
+            "application/json;q=high, text/html;q=0.1"
+        )));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_matches("*.woff2", "font.woff2"));
+        assert!(glob_matches("*.woff2", ".woff2"));
+        assert!(!glob_matches("*.woff2", "font.woff"));
+        assert!(glob_matches("app.*.js", "app.3f9a2b1c.js"));
+        assert!(!glob_matches("app.*.js", "vendor.3f9a2b1c.js"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("**x*", "abxcd"));
+    }
+
+    #[test]
+    fn glob_single_characters() {
+        assert!(glob_matches("v?.css", "v1.css"));
+        assert!(!glob_matches("v?.css", "v.css"));
+        assert!(!glob_matches("v?.css", "v10.css"));
+        // one character, not one byte
+        assert!(glob_matches("?.txt", "é.txt"));
+        assert!(glob_matches("é*", "été"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "a"));
+        assert!(!glob_matches("a", ""));
+    }
+
+    #[test]
+    fn connection_options() {
+        assert!(wants_close(&headers(&["Connection: close"])));
+        assert!(wants_close(&headers(&["connection: Keep-Alive, CLOSE"])));
+        assert!(wants_close(&headers(&[
+            "Connection: upgrade",
+            "Connection: close"
+        ])));
+        // an ignored h2c upgrade keeps the connection open
+        assert!(!wants_close(&headers(&[
+            "Connection: Upgrade, HTTP2-Settings"
+        ])));
+        assert!(!wants_close(&headers(&[
+            "X-Connection: close",
+            "Host: closed"
+        ])));
+        assert!(!wants_close(&headers(&["Connection: closed"])));
+    }
+
+    #[test]
+    fn folded_header_lines_are_malformed() {
+        assert!(has_malformed_headers(&headers(&[
+            "Host: localhost",
+            "X-Long: first",
+            " continued",
+        ])));
+        assert!(has_malformed_headers(&headers(&[
+            "X-Long: first",
+            "\tcontinued"
+        ])));
+        assert!(!has_malformed_headers(&headers(&[
+            "Host: localhost",
+            "X-Spaced:   value  ",
+        ])));
+    }
+
+    #[test]
+    fn duplicate_host_is_malformed() {
+        assert!(has_malformed_headers(&headers(&[
+            "Host: localhost",
+            "host: evil.example",
+        ])));
+        assert!(has_malformed_headers(&headers(&[
+            "Host: localhost",
+            "Host: localhost",
+        ])));
+        // other headers may repeat
+        assert!(!has_malformed_headers(&headers(&[
+            "Host: localhost",
+            "Accept: text/html",
+            "Accept: text/plain",
+        ])));
+    }
+
+    #[test]
+    fn header_order_of_a_basic_200() {
+        let response = build_http_response(Status::Success, "text/plain", Cow::Borrowed(b"hi"))
+            .with_header("x-request-id", "7".to_string())
+            .with_header("cache-control", "no-cache".to_string())
+            .with_header("etag", "\"x\"".to_string())
+            .with_header("accept-ranges", "bytes".to_string())
+            .with_header("SERVER", "test".to_string());
+        let mut wire = Vec::new();
+        response.write_to(&mut wire).unwrap();
+        let wire = String::from_utf8(wire).unwrap();
+        let names: Vec<&str> = wire
+            .lines()
+            .skip(1)
+            .map_while(|line| line.split_once(": ").map(|(name, _)| name))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "Date",
+                "Server",
+                "Content-Type",
+                "Content-Length",
+                "Cache-Control",
+                "Accept-Ranges",
+                "ETag",
+                "X-Request-Id",
+            ]
+        );
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\nDate: "), "{}", wire);
+        assert!(wire.ends_with("X-Request-Id: 7\r\n\r\nhi"), "{}", wire);
+    }
+
+    #[test]
+    fn bodyless_statuses_have_no_length() {
+        for status in [Status::NoContent, Status::NotModified] {
+            let response = build_http_response(status, "text/plain", Cow::Borrowed(b"body"))
+                .with_header("ETag", "\"x\"".to_string())
+                .with_header("Content-Length", "4".to_string());
+            let wire = written(response);
+            assert!(!wire.contains("Content-Length"), "{}", wire);
+            assert!(wire.contains("ETag: \"x\"\r\n"), "{}", wire);
+            assert!(wire.ends_with("\r\n\r\n"), "{}", wire);
         }
     }
+
+    #[test]
+    fn head_of_bodyless_status_has_no_length() {
+        let response =
+            build_http_response(Status::NotModified, "text/plain", Cow::Borrowed(b"body"));
+        let wire = written(response.into_head());
+        assert!(!wire.contains("Content-Length"), "{}", wire);
+    }
+
+    #[test]
+    fn byte_range_first_and_last() {
+        assert_eq!(byte_range("bytes=0-9", 100), Some(Some((0, 9))));
+        assert_eq!(byte_range("bytes=10-10", 100), Some(Some((10, 10))));
+        // a last offset past the end is cut to the file
+        assert_eq!(byte_range("bytes=90-500", 100), Some(Some((90, 99))));
+    }
+
+    #[test]
+    fn byte_range_suffix() {
+        assert_eq!(byte_range("bytes=-10", 100), Some(Some((90, 99))));
+        assert_eq!(byte_range("bytes=-500", 100), Some(Some((0, 99))));
+        assert_eq!(byte_range("bytes=-0", 100), Some(None));
+    }
+
+    #[test]
+    fn byte_range_open_ended() {
+        assert_eq!(byte_range("bytes=40-", 100), Some(Some((40, 99))));
+        assert_eq!(byte_range("bytes=99-", 100), Some(Some((99, 99))));
+    }
+
+    #[test]
+    fn byte_range_unsatisfiable() {
+        assert_eq!(byte_range("bytes=100-", 100), Some(None));
+        assert_eq!(byte_range("bytes=200-300", 100), Some(None));
+        assert_eq!(byte_range("bytes=0-", 0), Some(None));
+        assert_eq!(byte_range("bytes=-10", 0), Some(None));
+    }
+
+    #[test]
+    fn byte_range_ignored() {
+        // several ranges fall back to the whole representation
+        assert_eq!(byte_range("bytes=0-1,5-9", 100), None);
+        assert_eq!(byte_range("items=0-9", 100), None);
+        assert_eq!(byte_range("bytes=9-0", 100), None);
+        assert_eq!(byte_range("bytes=a-9", 100), None);
+        assert_eq!(byte_range("bytes=+1-9", 100), None);
+        assert_eq!(byte_range("bytes=-", 100), None);
+        assert_eq!(byte_range("bytes 0-9", 100), None);
+    }
+
+    const FOUR_GIB: u64 = 1 << 32;
+
+    #[test]
+    fn byte_range_past_four_gib() {
+        let len = FOUR_GIB + 1024;
+        assert_eq!(
+            byte_range("bytes=4294967295-4294967296", len),
+            Some(Some((FOUR_GIB - 1, FOUR_GIB)))
+        );
+        assert_eq!(
+            byte_range("bytes=4294967296-", len),
+            Some(Some((FOUR_GIB, len - 1)))
+        );
+        assert_eq!(
+            byte_range("bytes=-2048", len),
+            Some(Some((FOUR_GIB - 1024, len - 1)))
+        );
+        assert_eq!(byte_range("bytes=0-", len), Some(Some((0, len - 1))));
+        assert_eq!(byte_range("bytes=4294968320-", len), Some(None));
+    }
+
+    #[test]
+    fn byte_range_at_the_end_of_u64() {
+        let len = u64::MAX;
+        assert_eq!(
+            byte_range("bytes=18446744073709551614-", len),
+            Some(Some((len - 1, len - 1)))
+        );
+        // does not fit in a u64 at all
+        assert_eq!(byte_range("bytes=18446744073709551616-", len), None);
+    }
+
+    #[test]
+    fn head_announces_lengths_past_four_gib() {
+        let response = Response {
+            status: Status::Success,
+            headers: vec![],
+            body: Body::Sized(
+                FOUR_GIB + 1,
+                Box::new(|_| unreachable!("HEAD reads nothing")),
+            ),
+        };
+        let wire = written(response.into_head());
+        assert!(wire.contains("Content-Length: 4294967297\r\n"), "{}", wire);
+    }
+
+    #[test]
+    fn range_applies_without_if_range() {
+        assert!(range_applies(
+            &headers(&["Range: bytes=0-9"]),
+            "\"a\"",
+            None
+        ));
+    }
+
+    #[test]
+    fn range_applies_strong_etag() {
+        let strong = headers(&["If-Range: \"abc\""]);
+        assert!(range_applies(&strong, "\"abc\"", None));
+        assert!(!range_applies(&strong, "\"abd\"", None));
+    }
+
+    #[test]
+    fn range_applies_never_weak() {
+        // a weak validator is not good enough to splice ranges
+        assert!(!range_applies(
+            &headers(&["If-Range: W/\"abc\""]),
+            "W/\"abc\"",
+            None
+        ));
+        assert!(!range_applies(
+            &headers(&["If-Range: \"abc\""]),
+            "W/\"abc\"",
+            None
+        ));
+    }
+
+    #[test]
+    fn range_applies_date() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let exact = headers(&["If-Range: Tue, 14 Nov 2023 22:13:20 GMT"]);
+        assert!(range_applies(&exact, "W/\"x\"", Some(modified)));
+        let earlier = headers(&["If-Range: Tue, 14 Nov 2023 22:13:19 GMT"]);
+        assert!(!range_applies(&earlier, "W/\"x\"", Some(modified)));
+        assert!(!range_applies(&exact, "W/\"x\"", None));
+    }
+
+    #[test]
+    fn absolute_form_targets() {
+        assert_eq!(
+            absolute_form("http://example.com/a/b?c"),
+            Some(("example.com", "/a/b?c"))
+        );
+        assert_eq!(
+            absolute_form("HTTP://example.com:8080/"),
+            Some(("example.com:8080", "/"))
+        );
+        assert_eq!(absolute_form("https://[::1]/x"), Some(("[::1]", "/x")));
+        assert_eq!(
+            absolute_form("http://example.com"),
+            Some(("example.com", ""))
+        );
+        assert_eq!(
+            absolute_form("http://example.com?q"),
+            Some(("example.com", "?q"))
+        );
+    }
+
+    #[test]
+    fn other_target_forms() {
+        assert_eq!(absolute_form("/a"), None);
+        assert_eq!(absolute_form("http:///a"), None);
+        assert_eq!(absolute_form("ftp://example.com/a"), None);
+        assert_eq!(absolute_form("example.com/a"), None);
+        assert_eq!(absolute_form("*"), None);
+        assert_eq!(absolute_form(""), None);
+    }
+
+    #[test]
+    fn host_names() {
+        assert_eq!(parse_host_address("Host: example.com"), Some("example.com"));
+        assert_eq!(
+            parse_host_address("Host: example.com:8080"),
+            Some("example.com")
+        );
+        assert_eq!(
+            parse_host_address("Host: example.com:"),
+            Some("example.com")
+        );
+        assert_eq!(
+            parse_host_address("Host: example.com\r\n"),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn host_fqdn_form() {
+        assert_eq!(
+            parse_host_address("Host: example.com."),
+            Some("example.com")
+        );
+        assert_eq!(
+            parse_host_address("Host: example.com.:8080"),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn host_ipv6() {
+        assert_eq!(parse_host_address("Host: [::1]"), Some("[::1]"));
+        assert_eq!(parse_host_address("Host: [::1]:8080"), Some("[::1]"));
+        assert_eq!(parse_host_address("Host: [fe80::1]x"), None);
+    }
+
+    #[test]
+    fn host_empty() {
+        assert_eq!(parse_host_address("Host: "), None);
+        assert_eq!(parse_host_address("Host: :8080"), Some(""));
+    }
+
+    #[test]
+    fn host_invalid_port() {
+        assert_eq!(parse_host_address("Host: example.com:http"), None);
+        assert_eq!(parse_host_address("Host: example.com:80:80"), None);
+        assert_eq!(parse_host_address("Host: [::1]:x"), None);
+    }
+
+    #[test]
+    fn host_other_headers() {
+        assert_eq!(parse_host_address("Accept: */*"), None);
+        assert_eq!(parse_host_address("X-Host: example.com"), None);
+    }
 }
@@ -0,0 +1,68 @@
+// Maintenance mode: every request for a site is answered 503 with a Retry-After
+// hint, without stopping the server. It is switched on for the whole run with
+// `--maintenance`, or for as long as the `--maintenance-file` sentinel exists.
+// The built-in endpoints keep answering, so monitoring sees the state.
+use std::{borrow::Cow, fs, io, path::PathBuf};
+
+use crate::{build_error_response, build_http_response, Framing, Response, Status};
+
+enum Page {
+    Builtin,
+    // replaced by its contents at startup, requests never read it
+    File(PathBuf),
+    Loaded(Vec<u8>),
+}
+
+pub(crate) struct Maintenance {
+    always: bool,
+    sentinel: Option<PathBuf>,
+    retry_after: u64,
+    page: Page,
+}
+
+impl Maintenance {
+    pub(crate) fn new(
+        always: bool,
+        sentinel: Option<PathBuf>,
+        retry_after: u64,
+        page: Option<PathBuf>,
+    ) -> Self {
+        Maintenance {
+            always,
+            sentinel,
+            retry_after,
+            page: page.map_or(Page::Builtin, Page::File),
+        }
+    }
+
+    pub(crate) fn load(&mut self) -> io::Result<()> {
+        if let Page::File(p) = &self.page {
+            self.page = Page::Loaded(fs::read(p)?);
+        }
+        Ok(())
+    }
+
+    fn active(&self) -> bool {
+        self.always || self.sentinel.as_ref().is_some_and(|p| p.exists())
+    }
+
+    /// The 503 to send instead of the requested resource, if in maintenance.
+    pub(crate) fn response(&self) -> Option<Response> {
+        if !self.active() {
+            return None;
+        }
+        let response = match &self.page {
+            Page::Loaded(page) => build_http_response(
+                Status::ServiceUnavailable,
+                "text/html; charset=utf-8",
+                Cow::Owned(page.clone()),
+            )
+            .with_header("Cache-Control", "no-store".to_string()),
+            // every page is loaded before the server starts
+            Page::Builtin | Page::File(_) => {
+                build_error_response(Status::ServiceUnavailable, Framing::Safe)
+            }
+        };
+        Some(response.with_header("Retry-After", self.retry_after.to_string()))
+    }
+}
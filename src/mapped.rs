@@ -0,0 +1,23 @@
+// File bodies sent straight from a memory mapping (`--mmap`, built with the
+// `mmap` feature) instead of being copied through a buffer.
+//
+// The mapping is only read up to the length taken when the file was opened, but
+// a file truncated in the meantime has no pages left to read: touching them
+// kills the process with SIGBUS. Only use --mmap on trees whose files are
+// replaced by renaming a new copy into place, never rewritten in place.
+use std::{
+    fs::File,
+    io::{self, Write},
+};
+
+use memmap2::MmapOptions;
+
+/// Writes `len` bytes of `file`, starting at `offset`, from a mapping.
+pub(crate) fn write(file: &File, offset: u64, len: u64, w: &mut dyn Write) -> io::Result<()> {
+    let len = usize::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+    // SAFETY: the mapping is read-only and dropped before this returns, see the
+    // module comment for what happens when the file is truncated meanwhile
+    let map = unsafe { MmapOptions::new().offset(offset).len(len).map(file)? };
+    w.write_all(&map)
+}
@@ -0,0 +1,82 @@
+// Connection counters, served by the built-in `metrics` endpoint so saturation
+// shows up somewhere other than a scrolled-away line on stderr. Accept errors
+// that come in bursts, typically running out of file descriptors, are also
+// reported with a warning.
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{build_http_response, internal::Handler, Response, Status};
+
+static CONNECTIONS_ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static ACCEPT_ERRORS: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_REJECTED: AtomicU64 = AtomicU64::new(0);
+static REQUESTS_THROTTLED: AtomicU64 = AtomicU64::new(0);
+
+// accept errors within one second that are worth a warning
+const ACCEPT_ERROR_SPIKE: u64 = 10;
+const SPIKE_WINDOW: Duration = Duration::from_secs(1);
+// (start of the current window, accept errors in it)
+static SPIKE: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+
+pub(crate) fn connection_accepted() {
+    CONNECTIONS_ACCEPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counted against `--max-connections-per-ip`.
+pub(crate) fn connection_rejected() {
+    CONNECTIONS_REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counted against `--global-rate-limit`.
+pub(crate) fn request_throttled() {
+    REQUESTS_THROTTLED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn accept_failed() {
+    ACCEPT_ERRORS.fetch_add(1, Ordering::Relaxed);
+    let mut spike = match SPIKE.lock() {
+        Ok(spike) => spike,
+        Err(_) => return,
+    };
+    let now = Instant::now();
+    let (started, errors) = match *spike {
+        Some((started, errors)) if now.duration_since(started) < SPIKE_WINDOW => {
+            (started, errors + 1)
+        }
+        _ => (now, 1),
+    };
+    *spike = Some((started, errors));
+    // once per window, when the threshold is crossed
+    if errors == ACCEPT_ERROR_SPIKE {
+        eprintln!(
+            "Warning: {} accept errors within {:?}, connections are being lost",
+            errors, SPIKE_WINDOW
+        );
+    }
+}
+
+pub(crate) struct Metrics;
+
+impl Handler for Metrics {
+    fn handle(&self, _path: &str, _actual_headers: &[String]) -> Response {
+        let body = format!(
+            "connections_accepted {}\naccept_errors {}\nconnections_rejected {}\nrequests_throttled {}\n",
+            CONNECTIONS_ACCEPTED.load(Ordering::Relaxed),
+            ACCEPT_ERRORS.load(Ordering::Relaxed),
+            CONNECTIONS_REJECTED.load(Ordering::Relaxed),
+            REQUESTS_THROTTLED.load(Ordering::Relaxed),
+        );
+        build_http_response(
+            Status::Success,
+            "text/plain; charset=utf-8",
+            Cow::Owned(body.into_bytes()),
+        )
+        .with_header("Cache-Control", "no-store".to_string())
+    }
+}
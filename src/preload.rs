@@ -0,0 +1,255 @@
+// Preload hints from a manifest (`--preload-manifest`), mapping request paths to
+// the resources their HTML page will need:
+//
+//     { "/index.html": [ { "href": "/style.css", "as": "style" } ] }
+//
+// Only the small JSON subset the manifest needs is understood.
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::normalize_url_path;
+
+// destinations a preload may name, anything else is probably a typo
+const DESTINATIONS: [&str; 10] = [
+    "audio", "document", "fetch", "font", "image", "script", "style", "track", "video", "worker",
+];
+
+pub(crate) struct PreloadManifest {
+    // request path -> ready-made Link header values
+    links: HashMap<String, Vec<String>>,
+}
+
+enum Json {
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .src
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.src.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at byte {}", byte as char, self.pos))
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.src.get(self.pos).copied()
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Arr(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Arr(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = vec![];
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Obj(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Obj(members))
+            }
+            _ => Err(format!(
+                "expected a string, array or object at byte {}",
+                self.pos
+            )),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut out = vec![];
+        loop {
+            let byte = *self.src.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self.src.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    out.push(match escaped {
+                        b'"' | b'\\' | b'/' => escaped,
+                        // control characters have no business in a path or a destination
+                        _ => return Err(format!("unsupported escape at byte {}", self.pos - 1)),
+                    });
+                }
+                _ => out.push(byte),
+            }
+        }
+        String::from_utf8(out).map_err(|_| "string is not UTF-8".to_string())
+    }
+}
+
+// the href is pasted between angle brackets in a header value
+fn valid_href(href: &str) -> bool {
+    href.starts_with('/')
+        && href
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !matches!(b, b'<' | b'>' | b'"' | b','))
+}
+
+fn link_value(entry: &Json) -> Result<String, String> {
+    let members = match entry {
+        Json::Obj(members) => members,
+        _ => return Err("a preload target must be an object".to_string()),
+    };
+    let field = |name: &str| {
+        members.iter().find_map(|(key, value)| match value {
+            Json::Str(value) if key == name => Some(value.as_str()),
+            _ => None,
+        })
+    };
+    let href = field("href").ok_or("a preload target needs an \"href\" string")?;
+    let destination = field("as").ok_or("a preload target needs an \"as\" string")?;
+    preload_link(href, destination)
+}
+
+fn preload_link(href: &str, destination: &str) -> Result<String, String> {
+    if !valid_href(href) {
+        return Err(format!("invalid href {:?}", href));
+    }
+    if !DESTINATIONS.contains(&destination) {
+        return Err(format!(
+            "invalid \"as\" destination {:?} for {}",
+            destination, href
+        ));
+    }
+    Ok(format!("<{}>; rel=preload; as={}", href, destination))
+}
+
+/// The Link value for a `--preload <path>;as=<destination>` option, sent with
+/// every HTML page.
+pub(crate) fn parse_option(option: &str) -> Option<String> {
+    let (href, destination) = option.split_once(';')?;
+    let destination = destination.trim().strip_prefix("as=")?;
+    preload_link(href.trim(), destination.trim()).ok()
+}
+
+impl PreloadManifest {
+    pub(crate) fn load(p: &Path) -> Result<Self, String> {
+        let src = fs::read_to_string(p).map_err(|e| e.to_string())?;
+        let mut parser = Parser {
+            src: src.as_bytes(),
+            pos: 0,
+        };
+        let root = parser.value()?;
+        if parser.peek().is_some() {
+            return Err(format!("trailing data at byte {}", parser.pos));
+        }
+        let pages = match root {
+            Json::Obj(pages) => pages,
+            _ => return Err("the manifest must be an object keyed by request path".to_string()),
+        };
+        let mut links = HashMap::new();
+        for (page, targets) in pages {
+            // looked up the way requests are, `/docs/` and `/docs` are one page
+            let path = match normalize_url_path(&page) {
+                Some(path) if page.starts_with('/') => path,
+                _ => return Err(format!("{}: expected a request path", page)),
+            };
+            let targets = match targets {
+                Json::Arr(targets) => targets,
+                _ => return Err(format!("{}: expected an array of preload targets", page)),
+            };
+            let values = targets
+                .iter()
+                .map(link_value)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("{}: {}", page, e))?;
+            links.insert(path, values);
+        }
+        Ok(PreloadManifest { links })
+    }
+
+    /// The Link values for the page a request target names, whatever its query
+    /// and however its path is spelled.
+    pub(crate) fn links(&self, resource: &str) -> &[String] {
+        normalize_url_path(resource)
+            .and_then(|path| self.links.get(&path))
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    fn manifest(json: &str) -> Result<PreloadManifest, String> {
+        // tests run in parallel, each needs a file of its own
+        let n = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+        let p = std::env::temp_dir().join(format!("preload-{}-{}.json", std::process::id(), n));
+        fs::write(&p, json).unwrap();
+        let loaded = PreloadManifest::load(&p);
+        let _ = fs::remove_file(&p);
+        loaded
+    }
+
+    #[test]
+    fn looked_up_by_path() {
+        let manifest =
+            manifest(r#"{ "/docs/": [ { "href": "/docs.css", "as": "style" } ] }"#).unwrap();
+        let link = ["</docs.css>; rel=preload; as=style".to_string()];
+        assert_eq!(manifest.links("/docs"), link);
+        assert_eq!(manifest.links("/docs/"), link);
+        assert_eq!(manifest.links("/docs?page=2"), link);
+        assert_eq!(manifest.links("//docs/./"), link);
+        assert_eq!(manifest.links("/api/../docs"), link);
+        assert!(manifest.links("/docs/intro").is_empty());
+        assert!(manifest.links("/../docs").is_empty());
+    }
+
+    #[test]
+    fn keys_are_request_paths() {
+        assert!(manifest(r#"{ "docs": [] }"#).is_err());
+        assert!(manifest(r#"{ "/../docs": [] }"#).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+// Global request ceiling (`--global-rate-limit`), a token bucket shared by every
+// connection, so that many well-behaved clients together cannot overwhelm what
+// sits behind the server either. The bucket holds a second's worth of requests.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub(crate) struct RateLimit {
+    per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: u64,
+    // when the tokens were last topped up, only moved on by whole tokens
+    refilled: Instant,
+}
+
+impl RateLimit {
+    pub(crate) fn new(per_second: u64) -> Self {
+        RateLimit {
+            per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: per_second,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token for one request, false when the ceiling has been reached.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut bucket = match self.bucket.lock() {
+            Ok(bucket) => bucket,
+            Err(_) => return true,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled);
+        // in u128, a long idle stretch times a high rate does not fit a u64
+        let per_second = u128::from(self.per_second);
+        let earned = (elapsed.as_micros() * per_second / 1_000_000).min(per_second) as u64;
+        if earned > 0 {
+            bucket.tokens = bucket.tokens.saturating_add(earned).min(self.per_second);
+            // a full bucket loses what it would have earned meanwhile
+            bucket.refilled = match bucket.tokens {
+                full if full == self.per_second => now,
+                _ => {
+                    let spent = u128::from(earned) * 1_000_000 / per_second;
+                    bucket.refilled + Duration::from_micros(spent as u64)
+                }
+            };
+        }
+        match bucket.tokens {
+            0 => false,
+            _ => {
+                bucket.tokens -= 1;
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// Redirect rules loaded from a file (`--redirects`), one `from to [code]` rule per
+// line, for sites whose URLs moved:
+//
+//     # blank lines and comments are skipped
+//     /old-page    /new-page
+//     /blog/*      https://blog.example.com/*   308
+//
+// A `from` ending in `*` matches every path below it, the rest of the path is
+// appended to the target (after dropping a `*` there). Exact rules win over
+// wildcards, and longer wildcards over shorter ones. Rules apply only once the
+// request has passed the Host checks.
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{is_valid_header, Status};
+
+const DEFAULT_CODE: u16 = 301;
+
+pub(crate) struct Redirects {
+    // path -> (target, status code)
+    exact: HashMap<String, (String, u16)>,
+    // (prefix, target, status code), longest prefix first
+    wildcards: Vec<(String, String, u16)>,
+}
+
+fn parse_rule(line: &str) -> Result<(&str, &str, u16), String> {
+    let mut fields = line.split_whitespace();
+    let (from, to) = match (fields.next(), fields.next()) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err("expected `from to [code]`".to_string()),
+    };
+    let code = match fields.next() {
+        Some(code) => code
+            .parse()
+            .ok()
+            .filter(|code| Status::redirect(*code, String::new()).is_some())
+            .ok_or_else(|| format!("{} is not a redirect status", code))?,
+        None => DEFAULT_CODE,
+    };
+    if fields.next().is_some() {
+        return Err("unexpected text after the status code".to_string());
+    }
+    if !from.starts_with('/') || from.trim_end_matches('*').contains('*') {
+        return Err(format!("{} must be a path, with `*` only at its end", from));
+    }
+    if !is_valid_header("Location", to) {
+        return Err(format!("{} cannot be sent as a Location", to));
+    }
+    Ok((from, to, code))
+}
+
+impl Redirects {
+    /// Reads every rule, reporting the first malformed line by number.
+    pub(crate) fn load(p: &Path) -> Result<Self, String> {
+        let src = fs::read_to_string(p).map_err(|e| e.to_string())?;
+        let mut redirects = Redirects {
+            exact: HashMap::new(),
+            wildcards: vec![],
+        };
+        for (number, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (from, to, code) =
+                parse_rule(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            match from.strip_suffix('*') {
+                Some(prefix) => {
+                    let target = to.strip_suffix('*').unwrap_or(to);
+                    redirects
+                        .wildcards
+                        .push((prefix.to_string(), target.to_string(), code));
+                }
+                None => {
+                    redirects
+                        .exact
+                        .insert(from.to_string(), (to.to_string(), code));
+                }
+            }
+        }
+        redirects
+            .wildcards
+            .sort_by_key(|(prefix, _, _)| std::cmp::Reverse(prefix.len()));
+        Ok(redirects)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len()
+    }
+
+    /// The redirect for `resource`, if a rule covers it. A query string is not
+    /// matched on but carried over to a target that has none of its own.
+    pub(crate) fn find(&self, resource: &str) -> Option<Status> {
+        let (path, query) = match resource.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (resource, None),
+        };
+        let (mut target, code) = match self.exact.get(path) {
+            Some((target, code)) => (target.clone(), *code),
+            None => self.wildcards.iter().find_map(|(prefix, target, code)| {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                Some((format!("{}{}", target, rest), *code))
+            })?,
+        };
+        if let Some(query) = query.filter(|_| !target.contains('?')) {
+            target.push('?');
+            target.push_str(query);
+        }
+        Status::redirect(code, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    fn redirects(rules: &str) -> Result<Redirects, String> {
+        // tests run in parallel, each needs a file of its own
+        let n = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+        let p = std::env::temp_dir().join(format!("redirects-{}-{}.txt", std::process::id(), n));
+        fs::write(&p, rules).unwrap();
+        let loaded = Redirects::load(&p);
+        let _ = fs::remove_file(&p);
+        loaded
+    }
+
+    // (code, Location) of the redirect for `resource`
+    fn target(redirects: &Redirects, resource: &str) -> Option<(u16, String)> {
+        let status = redirects.find(resource)?;
+        let location = status.location().map(str::to_string);
+        Some((crate::from_status(status).0, location?))
+    }
+
+    const RULES: &str = "\
+        # moved pages\n\
+        /old-page  /new-page\n\
+        \n\
+        /blog/*    https://blog.example.com/*  308\n\
+        /blog/x/*  /x/   302\n\
+        /search    /find?q=all  307\n";
+
+    #[test]
+    fn exact_rules() {
+        let redirects = redirects(RULES).unwrap();
+        assert_eq!(redirects.len(), 4);
+        let moved = Some((301, "/new-page".to_string()));
+        assert_eq!(target(&redirects, "/old-page"), moved);
+        assert_eq!(target(&redirects, "/old-page/"), None);
+        assert_eq!(target(&redirects, "/old"), None);
+    }
+
+    #[test]
+    fn wildcard_rules() {
+        let redirects = redirects(RULES).unwrap();
+        assert_eq!(
+            target(&redirects, "/blog/2024/post"),
+            Some((308, "https://blog.example.com/2024/post".to_string()))
+        );
+        assert_eq!(
+            target(&redirects, "/blog/"),
+            Some((308, "https://blog.example.com/".to_string()))
+        );
+        // the longer prefix wins
+        assert_eq!(
+            target(&redirects, "/blog/x/y"),
+            Some((302, "/x/y".to_string()))
+        );
+        assert_eq!(target(&redirects, "/blog"), None);
+    }
+
+    #[test]
+    fn queries_are_carried_over() {
+        let redirects = redirects(RULES).unwrap();
+        assert_eq!(
+            target(&redirects, "/old-page?ref=a"),
+            Some((301, "/new-page?ref=a".to_string()))
+        );
+        assert_eq!(
+            target(&redirects, "/blog/post?x=1"),
+            Some((308, "https://blog.example.com/post?x=1".to_string()))
+        );
+        // a target with a query of its own keeps it
+        assert_eq!(
+            target(&redirects, "/search?q=mine"),
+            Some((307, "/find?q=all".to_string()))
+        );
+    }
+
+    #[test]
+    fn malformed_rules_name_their_line() {
+        let error = |rules| redirects(rules).err().unwrap_or_default();
+        assert!(error("/a /b\n/c\n").starts_with("line 2: "));
+        assert!(error("/a /b 200\n").contains("not a redirect status"));
+        assert!(error("/a /b 301 extra\n").starts_with("line 1: "));
+        assert!(error("a /b\n").contains("must be a path"));
+        assert!(error("/a*/b /c\n").contains("must be a path"));
+    }
+}
@@ -0,0 +1,223 @@
+// Graceful shutdown for either server: SIGINT/SIGTERM stop the accept loop,
+// idle keep-alive connections are woken and closed at once, and in-flight requests
+// get `--shutdown-timeout` to finish (announcing `Connection: close`) before their
+// sockets are torn down.
+use std::{
+    collections::HashMap,
+    net::{Shutdown, SocketAddrV4, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// bounds any further read of a connection that was busy when the drain began
+const DRAIN_READ_TIMEOUT_MS: u64 = 100;
+const DRAIN_POLL_INTERVAL_MS: u64 = 50;
+
+#[cfg(unix)]
+mod signal {
+    use std::{
+        net::{SocketAddrV4, TcpStream},
+        sync::atomic::Ordering,
+        thread,
+    };
+
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+
+    pub(super) fn install(wake: SocketAddrV4) {
+        // the handler itself only writes to signal-hook's self-pipe, the signals
+        // are acted on from an ordinary thread reading the other end
+        let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+            Ok(signals) => signals,
+            Err(e) => {
+                eprintln!("Failed to install signal handler: {}", e);
+                return;
+            }
+        };
+        let watcher = thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    super::SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+                    // an accept blocked on the listener returns with this
+                    // connection, which is dropped unanswered
+                    TcpStream::connect(wake).ok();
+                }
+            });
+        if let Err(e) = watcher {
+            eprintln!("Failed to spawn signal watcher: {}", e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod signal {
+    pub(super) fn install(_wake: std::net::SocketAddrV4) {}
+}
+
+/// Sets the shutdown flag on SIGINT and SIGTERM, then connects to `wake`, the
+/// listening address, so that the accept loop gets to see it.
+pub(crate) fn install_handler(wake: SocketAddrV4) {
+    signal::install(wake);
+}
+
+pub(crate) fn requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Drains the connections of a server that stopped accepting and reports how
+/// many finished on their own.
+pub(crate) fn finish(tracker: &ConnectionTracker, timeout: Duration) {
+    println!(
+        "Shutting down, waiting up to {:?} for connections to finish.",
+        timeout
+    );
+    let (drained, force_closed) = tracker.drain(timeout);
+    println!(
+        "Shutdown complete: {} connections drained, {} force-closed.",
+        drained, force_closed
+    );
+}
+
+/// Open connections (by a cloned handle, so they can be closed from the outside)
+/// and the number of requests currently being answered.
+#[derive(Default)]
+pub(crate) struct ConnectionTracker {
+    next_id: AtomicU64,
+    active_requests: AtomicUsize,
+    open: Mutex<HashMap<u64, OpenConnection>>,
+    // closed on their own once the shutdown was requested, counted under `open`'s lock
+    drained: AtomicUsize,
+}
+
+struct OpenConnection {
+    stream: TcpStream,
+    // answering a request rather than waiting for the next one
+    busy: bool,
+}
+
+pub(crate) struct ConnectionGuard<'a> {
+    tracker: &'a ConnectionTracker,
+    id: u64,
+}
+
+pub(crate) struct RequestGuard<'a> {
+    tracker: &'a ConnectionTracker,
+    connection: Option<u64>,
+}
+
+impl ConnectionTracker {
+    pub(crate) fn register(&self, stream: &TcpStream) -> Option<ConnectionGuard<'_>> {
+        let handle = stream.try_clone().ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let connection = OpenConnection {
+            stream: handle,
+            busy: false,
+        };
+        self.open.lock().ok()?.insert(id, connection);
+        Some(ConnectionGuard { tracker: self, id })
+    }
+
+    fn set_busy(&self, id: u64, busy: bool) {
+        if let Some(connection) = self
+            .open
+            .lock()
+            .ok()
+            .as_mut()
+            .and_then(|open| open.get_mut(&id))
+        {
+            connection.busy = busy;
+        }
+    }
+
+    /// Counts a request as in flight until the guard is dropped. A connection
+    /// marked busy this way is left to finish its response by `drain`.
+    pub(crate) fn begin_request(&self, connection: Option<&ConnectionGuard>) -> RequestGuard<'_> {
+        self.active_requests.fetch_add(1, Ordering::SeqCst);
+        let connection = connection.map(|connection| connection.id);
+        if let Some(id) = connection {
+            self.set_busy(id, true);
+        }
+        RequestGuard {
+            tracker: self,
+            connection,
+        }
+    }
+
+    fn open_connections(&self) -> usize {
+        self.open.lock().map_or(0, |open| open.len())
+    }
+
+    /// Waits for every connection to finish on its own within `timeout`, then
+    /// shuts down whatever is left. Returns (drained, force-closed) counts.
+    pub(crate) fn drain(&self, timeout: Duration) -> (usize, usize) {
+        if let Ok(open) = self.open.lock() {
+            for connection in open.values() {
+                if connection.busy {
+                    connection
+                        .stream
+                        .set_read_timeout(Some(Duration::from_millis(DRAIN_READ_TIMEOUT_MS)))
+                        .ok();
+                } else {
+                    // a new timeout would only apply to the next read, this wakes
+                    // the one already blocked waiting for another request
+                    connection.stream.shutdown(Shutdown::Read).ok();
+                }
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.active_requests.load(Ordering::SeqCst) == 0 && self.open_connections() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MS));
+        }
+
+        let mut force_closed = 0;
+        // under the lock a connection closing meanwhile is either drained or left here
+        let Ok(open) = self.open.lock() else {
+            return (self.drained.load(Ordering::SeqCst), 0);
+        };
+        for connection in open.values() {
+            connection.stream.shutdown(Shutdown::Both).ok();
+            force_closed += 1;
+        }
+        if force_closed > 0 {
+            eprintln!(
+                "Force-closing {} connections with {} requests still in flight.",
+                force_closed,
+                self.active_requests.load(Ordering::SeqCst)
+            );
+        }
+        (self.drained.load(Ordering::SeqCst), force_closed)
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.tracker.open.lock() {
+            // a connection that noticed the shutdown before the drain began counts too
+            if open.remove(&self.id).is_some() && requested() {
+                self.tracker.drained.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.connection {
+            self.tracker.set_busy(id, false);
+        }
+        self.tracker.active_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,66 @@
+// Fixed responses for exact paths given on the command line (`--route`), for
+// boilerplate such as robots.txt that should not need a file in every site.
+// They are answered before any filesystem resolution, for every host that
+// passes `--allowed-hosts` and `--canonical-host`.
+use std::{borrow::Cow, fs, io, path::PathBuf};
+
+use crate::{build_http_response, is_valid_header, Response, Status};
+
+enum RouteBody {
+    Inline(Vec<u8>),
+    // replaced by its contents at startup, requests never read it
+    File(PathBuf),
+}
+
+pub(crate) struct StaticRoute {
+    path: String,
+    content_type: String,
+    body: RouteBody,
+}
+
+impl StaticRoute {
+    /// Parses `<path>=<content-type>:<body>`, where a body of `@<file>` names a
+    /// file to read it from.
+    pub(crate) fn parse(spec: &str) -> Option<Self> {
+        let (path, response) = spec.split_once('=')?;
+        let (content_type, body) = response.split_once(':')?;
+        let content_type = content_type.trim();
+        if !path.starts_with('/') || !is_valid_header("Content-Type", content_type) {
+            return None;
+        }
+        let body = match body.strip_prefix('@') {
+            Some(file) => RouteBody::File(PathBuf::from(file)),
+            None => RouteBody::Inline(body.as_bytes().to_vec()),
+        };
+        Some(StaticRoute {
+            path: path.to_string(),
+            content_type: content_type.to_string(),
+            body,
+        })
+    }
+
+    pub(crate) fn load(&mut self) -> io::Result<()> {
+        if let RouteBody::File(p) = &self.body {
+            self.body = RouteBody::Inline(fs::read(p)?);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether `resource` is the route's path, ignoring any query string.
+    pub(crate) fn matches(&self, resource: &str) -> bool {
+        resource.split('?').next() == Some(self.path.as_str())
+    }
+
+    pub(crate) fn response(&self) -> Response {
+        let body = match &self.body {
+            RouteBody::Inline(body) => body.clone(),
+            // every route is loaded before the server starts
+            RouteBody::File(_) => vec![],
+        };
+        build_http_response(Status::Success, &self.content_type, Cow::Owned(body))
+    }
+}
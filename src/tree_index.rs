@@ -0,0 +1,119 @@
+// Startup index of every path below the served roots (`--index-tree`), so a
+// request for something that does not exist is answered without touching the
+// disk. Only hashes are kept, a collision merely sends a request on to the
+// filesystem. Paths created after the last refresh are not found until the next.
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+// about 8 MiB of hashes, a larger tree is served without the index
+const MAX_INDEXED_PATHS: usize = 1_000_000;
+
+pub(crate) struct TreeIndex {
+    roots: Vec<PathBuf>,
+    // None while the tree is too large to index
+    paths: RwLock<Option<HashSet<u64>>>,
+}
+
+fn path_hash(p: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    p.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn walk(roots: &[PathBuf]) -> Option<HashSet<u64>> {
+    let mut paths = HashSet::new();
+    for root in roots {
+        if let Ok(real) = root.canonicalize() {
+            walk_dir(root, &real, &mut vec![real.clone()], &mut paths)?;
+        }
+    }
+    Some(paths)
+}
+
+// Symlinked directories are followed like `resolve_path` follows them: only when
+// they stay inside the root, and never into one of their own ancestors.
+fn walk_dir(
+    dir: &Path,
+    root: &Path,
+    ancestors: &mut Vec<PathBuf>,
+    paths: &mut HashSet<u64>,
+) -> Option<()> {
+    paths.insert(path_hash(dir));
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Some(()),
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        match path.canonicalize() {
+            Ok(real) if real.is_dir() && real.starts_with(root) && !ancestors.contains(&real) => {
+                ancestors.push(real);
+                walk_dir(&path, root, ancestors, paths)?;
+                ancestors.pop();
+            }
+            _ => {
+                paths.insert(path_hash(&path));
+            }
+        }
+        if paths.len() > MAX_INDEXED_PATHS {
+            return None;
+        }
+    }
+    Some(())
+}
+
+impl TreeIndex {
+    pub(crate) fn build(roots: Vec<PathBuf>) -> Self {
+        let index = TreeIndex {
+            roots,
+            paths: RwLock::new(None),
+        };
+        index.refresh();
+        index
+    }
+
+    pub(crate) fn refresh(&self) {
+        let paths = walk(&self.roots);
+        match &paths {
+            Some(paths) => {
+                #[cfg(debug_assertions)]
+                println!("Indexed {} paths.", paths.len());
+            }
+            None => eprintln!(
+                "warning: more than {} paths below the document root, not indexing",
+                MAX_INDEXED_PATHS
+            ),
+        }
+        if let Ok(mut current) = self.paths.write() {
+            *current = paths;
+        }
+    }
+
+    /// False only when the index is certain `p` did not exist at the last refresh.
+    /// `p` must be free of dot segments, as `resolve_path` returns it.
+    pub(crate) fn may_exist(&self, p: &Path) -> bool {
+        match self.paths.read().as_deref() {
+            Ok(Some(paths)) => paths.contains(&path_hash(p)),
+            _ => true,
+        }
+    }
+
+    pub(crate) fn refresh_every(self: Arc<Self>, interval: Duration) {
+        let spawned = thread::Builder::new()
+            .name("tree-index".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                self.refresh();
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to start index refresh thread: {}", e);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// Read-only WebDAV (`--webdav-readonly`): PROPFIND describes a file or a
+// directory and, at Depth 1, its children, which is enough for file managers to
+// browse the tree. Every supported property is returned whatever the request
+// body asks for, and children are only listed when a GET for them would succeed.
+use std::{borrow::Cow, fs, path::Path, time::SystemTime};
+
+use crate::{
+    build_error_response, build_http_response, content_type, date, e_to_cow, find_header,
+    html_escape, resolve_path, resolve_target, Framing, ProgArgs, Response, Status,
+    DEFAULT_MEDIA_TYPE,
+};
+
+const MULTISTATUS_HEAD: &str =
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n";
+const MULTISTATUS_TAIL: &str = "</D:multistatus>\n";
+
+enum Kind {
+    Collection,
+    File { len: u64, content_type: String },
+}
+
+struct Entry {
+    href: String,
+    name: String,
+    kind: Kind,
+    modified: Option<SystemTime>,
+}
+
+fn describe(p: &Path, href: String, name: String, args: &ProgArgs) -> Option<Entry> {
+    let meta = fs::metadata(p).ok()?;
+    let kind = if meta.is_dir() {
+        Kind::Collection
+    } else {
+        // typed as a GET would send them, `LICENSE` included
+        let content_type = match p.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => content_type(args, ext),
+            None => DEFAULT_MEDIA_TYPE.to_string(),
+        };
+        Kind::File {
+            len: meta.len(),
+            content_type,
+        }
+    };
+    let href = match kind {
+        Kind::Collection if !href.ends_with('/') => href + "/",
+        _ => href,
+    };
+    Some(Entry {
+        href,
+        name,
+        kind,
+        modified: meta.modified().ok(),
+    })
+}
+
+fn push_response(xml: &mut String, entry: &Entry) {
+    xml.push_str("<D:response>\n");
+    xml.push_str(&format!("<D:href>{}</D:href>\n", html_escape(&entry.href)));
+    xml.push_str("<D:propstat>\n<D:prop>\n");
+    xml.push_str(&format!(
+        "<D:displayname>{}</D:displayname>\n",
+        html_escape(&entry.name)
+    ));
+    match &entry.kind {
+        Kind::Collection => xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n"),
+        Kind::File { len, content_type } => {
+            xml.push_str("<D:resourcetype/>\n");
+            xml.push_str(&format!(
+                "<D:getcontentlength>{}</D:getcontentlength>\n",
+                len
+            ));
+            xml.push_str(&format!(
+                "<D:getcontenttype>{}</D:getcontenttype>\n",
+                html_escape(content_type)
+            ));
+        }
+    }
+    if let Some(modified) = entry.modified {
+        xml.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>\n",
+            date::http_date(modified)
+        ));
+    }
+    xml.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n");
+    xml.push_str("</D:response>\n");
+}
+
+/// Answers PROPFIND for `resource` below `root`, hrefs are built from `url_base`.
+/// Only Depth 0 and 1 are supported, listings stop at `--autoindex-max-entries`.
+pub(crate) fn propfind(
+    root: &Path,
+    resource: &str,
+    url_base: &str,
+    actual_headers: &[String],
+    args: &ProgArgs,
+) -> Response {
+    let with_children = match find_header(actual_headers, "Depth") {
+        Some("0") => false,
+        Some("1") => true,
+        Some(depth) if !depth.eq_ignore_ascii_case("infinity") => {
+            return build_error_response(Status::BadRequest, Framing::Safe);
+        }
+        // a missing Depth means infinity, a walk of the whole tree is not offered
+        _ => return build_error_response(Status::Forbidden, Framing::Safe),
+    };
+
+    let p = if resource.trim_start_matches('/').is_empty() {
+        root.to_path_buf()
+    } else {
+        match resolve_target(root, resource, args) {
+            Ok(p) => p,
+            Err(response) => return response,
+        }
+    };
+    if let Err(e) = fs::metadata(&p) {
+        return e_to_cow(&p, e);
+    }
+    let name = match resource.trim_end_matches('/').rsplit('/').next() {
+        Some("") | None => "/",
+        Some(name) => name,
+    };
+    let target = match describe(
+        &p,
+        format!("{}{}", url_base, resource),
+        name.to_string(),
+        args,
+    ) {
+        Some(target) => target,
+        None => return build_error_response(Status::PageNotFound, Framing::Safe),
+    };
+
+    let mut children = vec![];
+    if with_children && matches!(target.kind, Kind::Collection) {
+        let entries = match fs::read_dir(&p) {
+            Ok(entries) => entries,
+            Err(e) => return e_to_cow(&p, e),
+        };
+        let parent = resource.trim_end_matches('/');
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if children.len() >= args.autoindex_max_entries {
+                break;
+            }
+            // names that are not UTF-8 cannot be requested anyway
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let child_resource = format!("{}/{}", parent, name);
+            let child = resolve_path(
+                root,
+                child_resource.trim_start_matches('/'),
+                args.follow_symlinks,
+            )
+            .and_then(|child| {
+                let href = format!("{}{}", url_base, child_resource);
+                describe(&child, href, name, args)
+            });
+            children.extend(child);
+        }
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    let mut xml = MULTISTATUS_HEAD.to_string();
+    for entry in std::iter::once(&target).chain(&children) {
+        push_response(&mut xml, entry);
+    }
+    xml.push_str(MULTISTATUS_TAIL);
+    build_http_response(
+        Status::Multistatus,
+        "application/xml; charset=utf-8",
+        Cow::Owned(xml.into_bytes()),
+    )
+}
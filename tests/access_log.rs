@@ -0,0 +1,66 @@
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn excluded_paths_are_served_but_not_logged() {
+    let root = DocRoot::new("access-log-exclude");
+    root.file("page.html", b"<p>page</p>\n")
+        .file("skip/x.txt", b"x\n");
+    let log = root.path().join("access.log");
+    let server = Server::start(
+        &root,
+        &[
+            "--access-log",
+            log.to_str().unwrap(),
+            "--access-log-exclude",
+            "/__",
+            "--access-log-exclude",
+            "/skip/",
+        ],
+    );
+    // one connection, lines are logged in request order
+    let wire = server.exchange(
+        b"GET /__health HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /skip/x.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /page.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    let text = String::from_utf8_lossy(&wire);
+    assert_eq!(text.matches("HTTP/1.1 200 OK\r\n").count(), 3, "{}", text);
+    assert!(text.ends_with("<p>page</p>\n"), "{}", text);
+
+    // written once the response is out, the last line marks the end
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let lines = loop {
+        let lines = fs::read_to_string(&log).unwrap_or_default();
+        if lines.contains("/page.html") || Instant::now() > deadline {
+            break lines;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(lines.lines().count(), 1, "{}", lines);
+    assert!(
+        lines.contains("\"GET /page.html HTTP/1.1\" 200 12"),
+        "{}",
+        lines
+    );
+
+    // excluded requests still count, the connection that made them included
+    let metrics =
+        server.exchange(b"GET /__metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&metrics), 200);
+    assert_eq!(
+        header(&metrics, "Cache-Control").as_deref(),
+        Some("no-store")
+    );
+    let counters = String::from_utf8_lossy(body(&metrics)).into_owned();
+    assert!(
+        counters.contains("connections_accepted 2\n"),
+        "{}",
+        counters
+    );
+}
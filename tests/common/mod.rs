@@ -0,0 +1,254 @@
+// Runs the built server on a free port against a document root made for the
+// test, and talks to it over plain sockets so that the bytes on the wire are
+// exactly what a client would get.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+const READ_TIMEOUT_SECS: u64 = 5;
+
+static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+/// The modification time of every file `DocRoot` writes, so that `ETag` and
+/// `Last-Modified` come out the same on every run.
+pub const MTIME_SECS: u64 = 1_700_000_000;
+
+pub struct DocRoot {
+    path: PathBuf,
+}
+
+impl DocRoot {
+    /// An empty document root with a `localhost` vhost directory.
+    pub fn new(name: &str) -> DocRoot {
+        let id = NEXT_ROOT.fetch_add(1, Ordering::Relaxed);
+        let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+            "{}-{}-{}",
+            name,
+            std::process::id(),
+            id
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("localhost")).unwrap();
+        DocRoot { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to `name` below the `localhost` vhost.
+    pub fn file(&self, name: &str, contents: &[u8]) -> &DocRoot {
+        let p = self.path.join("localhost").join(name);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(&p, contents).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(MTIME_SECS);
+        fs::File::options()
+            .write(true)
+            .open(&p)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        self
+    }
+
+    pub fn dir(&self, name: &str) -> &DocRoot {
+        fs::create_dir_all(self.path.join("localhost").join(name)).unwrap();
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn symlink(&self, name: &str, target: &str) -> &DocRoot {
+        std::os::unix::fs::symlink(target, self.path.join("localhost").join(name)).unwrap();
+        self
+    }
+}
+
+impl Drop for DocRoot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub struct Server {
+    child: Child,
+    port: u16,
+    stdout: Arc<Mutex<String>>,
+    reader: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Server {
+    /// Starts the server with `options` in front of the port and `root`, and waits
+    /// until it listens.
+    pub fn start(root: &DocRoot, options: &[&str]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_http_server"))
+            .args(options)
+            .arg("0")
+            .arg(root.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("server starts");
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        // startup notes such as the number of loaded redirects come first
+        let mut line = String::new();
+        while stdout.read_line(&mut line).unwrap() > 0
+            && !line
+                .lines()
+                .last()
+                .unwrap_or_default()
+                .starts_with("listening on")
+        {}
+        let port = line
+            .lines()
+            .last()
+            .unwrap_or_default()
+            .trim()
+            .strip_prefix("listening on address: http://")
+            .and_then(|addr| addr.rsplit(':').next())
+            .and_then(|port| port.parse().ok())
+            .unwrap_or_else(|| panic!("unexpected first line: {:?}", line));
+        // kept draining, a full pipe would block the server's request logging
+        let output = Arc::new(Mutex::new(line));
+        let collected = Arc::clone(&output);
+        let reader = std::thread::spawn(move || {
+            let mut line = String::new();
+            while stdout.read_line(&mut line).is_ok_and(|read| read > 0) {
+                collected.lock().unwrap().push_str(&line);
+                line.clear();
+            }
+        });
+        Server {
+            child,
+            port,
+            stdout: output,
+            reader: Some(reader),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// What the server printed so far.
+    pub fn stdout(&self) -> String {
+        self.stdout.lock().unwrap().clone()
+    }
+
+    /// Sends the server `signal` (`TERM`, `INT`) through kill(1).
+    #[cfg(unix)]
+    pub fn signal(&self, signal: &str) {
+        let sent = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(sent.success());
+    }
+
+    /// The exit status, once the server exits within `timeout`.
+    pub fn wait_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                // everything printed before the exit is collected
+                if let Some(reader) = self.reader.take() {
+                    reader.join().unwrap();
+                }
+                return Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.1.1", self.port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))
+            .unwrap();
+        stream
+    }
+
+    /// Sends `request` on a new connection and returns everything read until the
+    /// server closes it.
+    pub fn exchange(&self, request: &[u8]) -> Vec<u8> {
+        let mut stream = self.connect();
+        stream.write_all(request).unwrap();
+        let _ = stream.shutdown(Shutdown::Write);
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The response with the `Date` value and the server's port replaced, the only
+/// parts that differ between runs.
+pub fn normalise(response: &[u8], port: u16) -> Vec<u8> {
+    let head_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(response.len(), |i| i + 4);
+    let (head, body) = response.split_at(head_len);
+    let port = format!(":{}", port);
+    let head = String::from_utf8_lossy(head)
+        .split_inclusive("\r\n")
+        .map(|line| {
+            if line.to_ascii_lowercase().starts_with("date:") {
+                "Date: <date>\r\n".to_string()
+            } else {
+                line.replace(&port, ":<port>")
+            }
+        })
+        .collect::<String>();
+    let body = String::from_utf8(body.to_vec())
+        .map(|body| body.replace(&port, ":<port>").into_bytes())
+        .unwrap_or_else(|e| e.into_bytes());
+    [head.into_bytes(), body].concat()
+}
+
+/// The status code of a response.
+pub fn status(response: &[u8]) -> u16 {
+    String::from_utf8_lossy(response)
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or_else(|| panic!("no status line in {:?}", String::from_utf8_lossy(response)))
+}
+
+/// The value of the first `name` header of a response.
+pub fn header(response: &[u8], name: &str) -> Option<String> {
+    let text = String::from_utf8_lossy(response);
+    let head = text.split("\r\n\r\n").next().unwrap_or_default();
+    head.lines().skip(1).find_map(|line| {
+        let (field, value) = line.split_once(':')?;
+        field
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The body of a response.
+pub fn body(response: &[u8]) -> &[u8] {
+    response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(&[][..], |i| &response[i + 4..])
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+
+fn get(path: &str, extra: &str) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+        path, extra
+    )
+    .into_bytes()
+}
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("conditional");
+    root.file("page.html", b"<p>page</p>\n");
+    root
+}
+
+#[test]
+fn not_modified_has_validators_but_no_length() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let etag = header(&server.exchange(&get("/page.html", "")), "ETag").unwrap();
+    let response = server.exchange(&get("/page.html", &format!("If-None-Match: {}\r\n", etag)));
+    assert_eq!(status(&response), 304);
+    assert_eq!(header(&response, "Content-Length"), None);
+    assert_eq!(header(&response, "ETag"), Some(etag));
+    assert!(header(&response, "Last-Modified").is_some());
+    assert!(body(&response).is_empty());
+}
+
+const LAST_MODIFIED: &str = "Tue, 14 Nov 2023 22:13:20 GMT";
+
+fn if_modified_since(server: &Server, date: &str) -> u16 {
+    status(&server.exchange(&get(
+        "/page.html",
+        &format!("If-Modified-Since: {}\r\n", date),
+    )))
+}
+
+#[test]
+fn if_modified_since_compares_whole_seconds() {
+    let root = site();
+    // HTTP dates drop the sub-second part the file system keeps
+    let mtime =
+        std::time::UNIX_EPOCH + std::time::Duration::from_millis(common::MTIME_SECS * 1000 + 500);
+    std::fs::File::options()
+        .write(true)
+        .open(root.path().join("localhost/page.html"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&get("/page.html", ""));
+    assert_eq!(
+        header(&response, "Last-Modified").as_deref(),
+        Some(LAST_MODIFIED)
+    );
+    assert_eq!(if_modified_since(&server, LAST_MODIFIED), 304);
+    assert_eq!(
+        if_modified_since(&server, "Tue, 14 Nov 2023 22:13:21 GMT"),
+        304
+    );
+    assert_eq!(
+        if_modified_since(&server, "Tue, 14 Nov 2023 22:13:19 GMT"),
+        200
+    );
+}
+
+#[test]
+fn future_if_modified_since_is_ignored() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    assert_eq!(
+        if_modified_since(&server, "Fri, 01 Jan 2100 00:00:00 GMT"),
+        200
+    );
+}
+
+#[test]
+fn malformed_if_modified_since_is_ignored() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    for date in [
+        "yesterday",
+        "Tue, 14 Nov 2023 25:13:20 GMT",
+        "",
+        "1700000000",
+        "Sun, 06 Nov 400000000000 08:49:37 GMT",
+    ] {
+        assert_eq!(if_modified_since(&server, date), 200, "{:?}", date);
+    }
+}
+
+// `/` is not served in place, its index is a redirect away and the conditional
+// and range logic then runs against `index.html` itself
+#[test]
+fn conditional_get_to_the_root_follows_to_its_index() {
+    let root = site();
+    root.file("index.html", b"<h1>hello</h1>\n");
+    let server = Server::start(&root, &[]);
+    let validators = format!("If-Modified-Since: {}\r\n", LAST_MODIFIED);
+    let response = server.exchange(&get("/", &validators));
+    assert_eq!(status(&response), 301);
+    let location = header(&response, "Location").unwrap();
+    assert_eq!(
+        location,
+        format!("http://localhost:{}/index.html", server.port())
+    );
+    assert_eq!(header(&response, "Last-Modified"), None);
+    assert_eq!(header(&response, "ETag"), None);
+
+    let response = server.exchange(&get("/index.html", &validators));
+    assert_eq!(status(&response), 304);
+    let response = server.exchange(&get("/index.html", "Range: bytes=0-3\r\n"));
+    assert_eq!(status(&response), 206);
+    assert_eq!(body(&response), b"<h1>");
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use common::{header, DocRoot, Server};
+use std::io::{Read, Write};
+
+// one past the server's MAX_REQUESTS_PER_CONNECTION
+const PAST_THE_CAP: usize = 101;
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("connection");
+    root.file("a.txt", b"a\n");
+    root
+}
+
+/// Sends `count` pipelined requests in one write, the last asking to close when
+/// `close` is set, and splits what comes back into responses.
+fn pipelined(server: &Server, count: usize, close: bool) -> Vec<Vec<u8>> {
+    let mut requests = "GET /a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(count - 1);
+    requests.push_str("GET /a.txt HTTP/1.1\r\nHost: localhost\r\n");
+    if close {
+        requests.push_str("Connection: close\r\n");
+    }
+    requests.push_str("\r\n");
+    let mut stream = server.connect();
+    stream.write_all(requests.as_bytes()).unwrap();
+    let mut wire = Vec::new();
+    stream.read_to_end(&mut wire).unwrap();
+    // every body is `a\n`, no status line can hide inside one
+    String::from_utf8(wire)
+        .unwrap()
+        .split("HTTP/1.1 ")
+        .skip(1)
+        .map(|response| format!("HTTP/1.1 {}", response).into_bytes())
+        .collect()
+}
+
+#[test]
+fn debug_headers_count_pipelined_requests() {
+    let root = site();
+    let server = Server::start(&root, &["--debug-headers"]);
+    let responses = pipelined(&server, 3, true);
+    assert_eq!(responses.len(), 3);
+    for (i, response) in responses.iter().enumerate() {
+        assert_eq!(
+            header(response, "X-Requests-Served"),
+            Some((i + 1).to_string())
+        );
+        assert_eq!(
+            header(response, "X-Requests-Remaining"),
+            Some((PAST_THE_CAP - 2 - i).to_string())
+        );
+    }
+}
+
+#[test]
+fn connection_closes_at_the_request_cap() {
+    let root = site();
+    let server = Server::start(&root, &["--debug-headers"]);
+    // the requests past the cap are never answered, the server closes instead
+    let responses = pipelined(&server, PAST_THE_CAP + 5, false);
+    assert_eq!(responses.len(), PAST_THE_CAP - 1);
+    let last = responses.last().unwrap();
+    assert_eq!(header(last, "X-Requests-Remaining").as_deref(), Some("0"));
+    assert_eq!(header(last, "Connection").as_deref(), Some("close"));
+}
+
+#[test]
+fn no_debug_headers_by_default() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let responses = pipelined(&server, 2, true);
+    assert_eq!(responses.len(), 2);
+    assert!(responses
+        .iter()
+        .all(|response| header(response, "X-Requests-Served").is_none()));
+}
+
+#[test]
+fn h2c_upgrade_is_ignored() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let upgrade = "GET /a.txt HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n";
+    let next = "GET /a.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let mut stream = server.connect();
+    stream
+        .write_all(format!("{}{}", upgrade, next).as_bytes())
+        .unwrap();
+    let mut wire = Vec::new();
+    stream.read_to_end(&mut wire).unwrap();
+    let text = String::from_utf8(wire).unwrap();
+    // served as HTTP/1.1, and the connection stayed open for the next request
+    assert_eq!(text.matches("HTTP/1.1 200 OK\r\n").count(), 2, "{}", text);
+    assert!(!text.contains(" 101 "), "{}", text);
+    assert!(!text.to_ascii_lowercase().contains("upgrade"), "{}", text);
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::{header, status, DocRoot, Server};
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("cors");
+    root.file("data.json", b"{}\n");
+    root
+}
+
+fn from_origin(method: &str, extra: &str) -> Vec<u8> {
+    format!(
+        "{} /data.json HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\n{}Connection: close\r\n\r\n",
+        method, extra
+    )
+    .into_bytes()
+}
+
+#[test]
+fn credentialed_responses_never_allow_every_origin() {
+    let root = site();
+    let server = Server::start(&root, &["--cors-origin", "*", "--cors-credentials"]);
+    let preflight = from_origin(
+        "OPTIONS",
+        "Access-Control-Request-Method: GET\r\nAccess-Control-Request-Headers: x-token\r\n",
+    );
+    for request in [from_origin("GET", ""), from_origin("HEAD", ""), preflight] {
+        let response = server.exchange(&request);
+        assert!(matches!(status(&response), 200 | 204));
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin").as_deref(),
+            Some("https://app.example")
+        );
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Credentials").as_deref(),
+            Some("true")
+        );
+        let vary = header(&response, "Vary").unwrap_or_default();
+        assert!(vary.contains("Origin"), "{}", vary);
+    }
+}
+
+#[test]
+fn wildcard_without_credentials() {
+    let root = site();
+    let server = Server::start(&root, &["--cors-origin", "*"]);
+    let response = server.exchange(&from_origin("GET", ""));
+    assert_eq!(
+        header(&response, "Access-Control-Allow-Origin").as_deref(),
+        Some("*")
+    );
+    assert_eq!(header(&response, "Access-Control-Allow-Credentials"), None);
+}
+
+#[test]
+fn other_origins_get_no_cors_headers() {
+    let root = site();
+    let server = Server::start(
+        &root,
+        &[
+            "--cors-origin",
+            "https://other.example",
+            "--cors-credentials",
+        ],
+    );
+    let response = server.exchange(&from_origin("GET", ""));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+    assert_eq!(header(&response, "Access-Control-Allow-Credentials"), None);
+}
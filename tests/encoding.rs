@@ -0,0 +1,127 @@
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+
+fn get(path: &str, accept_encoding: &str) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+        path, accept_encoding
+    )
+    .into_bytes()
+}
+
+#[test]
+fn refused_identity_is_406() {
+    let root = DocRoot::new("encoding-406");
+    root.file("app.js", b"let a;\n");
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&get("/app.js", "br, identity;q=0"));
+    assert_eq!(status(&response), 406);
+    let response = server.exchange(&get("/app.js", "br"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Encoding"), None);
+}
+
+#[test]
+fn precompressed_sibling_is_negotiated() {
+    let root = DocRoot::new("encoding-sibling");
+    root.file("app.js", b"let a;\n").file("app.js.gz", b"gz");
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&get("/app.js", "gzip;q=0.5, identity;q=0.1"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(
+        header(&response, "Content-Encoding").as_deref(),
+        Some("gzip")
+    );
+    assert_eq!(
+        header(&response, "Vary").as_deref(),
+        Some("Accept-Encoding")
+    );
+    let response = server.exchange(&get("/app.js", "gzip;q=0, identity"));
+    assert_eq!(header(&response, "Content-Encoding"), None);
+}
+
+#[test]
+fn brotli_and_gzip_siblings_follow_client_preference() {
+    let root = DocRoot::new("encoding-br");
+    root.file("both.css", b"a {}\n")
+        .file("both.css.br", b"br")
+        .file("both.css.gz", b"gz")
+        .file("br-only.css", b"a {}\n")
+        .file("br-only.css.br", b"br")
+        .file("gz-only.css", b"a {}\n")
+        .file("gz-only.css.gz", b"gz")
+        .file("neither.css", b"a {}\n");
+    let server = Server::start(&root, &[]);
+    // (file, Accept-Encoding, coding sent, body)
+    let cases: [(&str, &str, Option<&str>, &[u8]); 11] = [
+        ("both.css", "gzip, br", Some("br"), b"br"),
+        ("both.css", "br;q=0.5, gzip", Some("gzip"), b"gz"),
+        ("both.css", "gzip;q=0.8, br;q=0.8", Some("br"), b"br"),
+        ("both.css", "gzip", Some("gzip"), b"gz"),
+        ("both.css", "identity", None, b"a {}\n"),
+        ("br-only.css", "gzip, br", Some("br"), b"br"),
+        ("br-only.css", "gzip", None, b"a {}\n"),
+        ("gz-only.css", "gzip, br", Some("gzip"), b"gz"),
+        ("gz-only.css", "br", None, b"a {}\n"),
+        ("neither.css", "gzip, br", None, b"a {}\n"),
+        ("neither.css", "*", None, b"a {}\n"),
+    ];
+    for (file, accept, coding, expected) in cases {
+        let response = server.exchange(&get(&format!("/{}", file), accept));
+        let case = format!("{} with {}", file, accept);
+        assert_eq!(status(&response), 200, "{}", case);
+        assert_eq!(
+            header(&response, "Content-Encoding").as_deref(),
+            coding,
+            "{}",
+            case
+        );
+        assert_eq!(body(&response), expected, "{}", case);
+        // the type of the original, not of the compressed file
+        assert_eq!(
+            header(&response, "Content-Type").as_deref(),
+            Some("text/css; charset=utf-8"),
+            "{}",
+            case
+        );
+        let vary = header(&response, "Vary");
+        assert_eq!(
+            vary.as_deref() == Some("Accept-Encoding"),
+            file != "neither.css",
+            "{}",
+            case
+        );
+    }
+}
+
+#[test]
+fn each_coding_has_its_own_etag() {
+    let root = DocRoot::new("encoding-etag");
+    root.file("app.js", b"let a;\n").file("app.js.gz", b"gz");
+    let server = Server::start(&root, &[]);
+    let identity = server.exchange(&get("/app.js", "identity"));
+    let gzip = server.exchange(&get("/app.js", "gzip"));
+    assert_eq!(header(&gzip, "Content-Encoding").as_deref(), Some("gzip"));
+    let (identity_etag, gzip_etag) = (
+        header(&identity, "ETag").unwrap(),
+        header(&gzip, "ETag").unwrap(),
+    );
+    assert_ne!(identity_etag, gzip_etag);
+    for response in [&identity, &gzip] {
+        assert_eq!(header(response, "Vary").as_deref(), Some("Accept-Encoding"));
+    }
+
+    // a validator only confirms the coding it was sent with
+    let conditional = |accept: &str, etag: &str| {
+        let request = format!(
+            "GET /app.js HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n",
+            accept, etag
+        );
+        status(&server.exchange(request.as_bytes()))
+    };
+    assert_eq!(conditional("gzip", &gzip_etag), 304);
+    assert_eq!(conditional("identity", &identity_etag), 304);
+    assert_eq!(conditional("gzip", &identity_etag), 200);
+    assert_eq!(conditional("identity", &gzip_etag), 200);
+}
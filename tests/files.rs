@@ -0,0 +1,218 @@
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+fn get(path: &str) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .into_bytes()
+}
+
+#[test]
+fn extensionless_file_is_served() {
+    let root = DocRoot::new("files-extensionless");
+    root.file("LICENSE", b"MIT\n");
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&get("/LICENSE"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(
+        header(&response, "Content-Type").as_deref(),
+        Some("application/octet-stream")
+    );
+    assert_eq!(body(&response), b"MIT\n");
+}
+
+#[test]
+fn missing_extensionless_file_is_404() {
+    let root = DocRoot::new("files-missing");
+    root.file("LICENSE", b"MIT\n");
+    let server = Server::start(&root, &[]);
+    assert_eq!(status(&server.exchange(&get("/COPYING"))), 404);
+    assert_eq!(status(&server.exchange(&get("/COPYING.txt"))), 404);
+}
+
+#[test]
+fn directory_redirect_uses_the_configured_code() {
+    let root = DocRoot::new("files-redirect-code");
+    root.file("docs/index.html", b"<h1>docs</h1>\n")
+        .file("notes.txt", b"notes\n");
+    let server = Server::start(
+        &root,
+        &[
+            "--index-redirect-code",
+            "302",
+            "--trailing-slash",
+            "redirect",
+        ],
+    );
+    let response = server.exchange(&get("/docs"));
+    assert_eq!(status(&response), 302);
+    assert_eq!(
+        header(&response, "Location"),
+        Some(format!(
+            "http://localhost:{}/docs/index.html",
+            server.port()
+        ))
+    );
+    // the trailing-slash redirect of a file follows the same option
+    let response = server.exchange(&get("/notes.txt/"));
+    assert_eq!(status(&response), 302);
+    assert_eq!(
+        header(&response, "Location"),
+        Some(format!("http://localhost:{}/notes.txt", server.port()))
+    );
+}
+
+#[test]
+fn directory_redirect_is_permanent_by_default() {
+    let root = DocRoot::new("files-redirect-default");
+    root.file("docs/index.html", b"<h1>docs</h1>\n");
+    let server = Server::start(&root, &[]);
+    assert_eq!(status(&server.exchange(&get("/docs"))), 301);
+}
+
+#[test]
+fn slow_reader_gets_the_whole_file() {
+    let root = DocRoot::new("files-slow-reader");
+    let contents: Vec<u8> = (0..=255).cycle().take(16 << 20).collect();
+    root.file("big.bin", &contents);
+    let server = Server::start(&root, &[]);
+    // a small receive window, so the server's writes time out while the client
+    // is not reading
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let addr = std::net::SocketAddr::from(([127, 0, 1, 1], server.port()));
+    socket.connect(&addr.into()).unwrap();
+    let mut stream = std::net::TcpStream::from(socket);
+    stream.write_all(&get("/big.bin")).unwrap();
+    std::thread::sleep(Duration::from_secs(3));
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(status(&response), 200);
+    assert!(body(&response) == contents.as_slice());
+}
+
+#[test]
+fn extensions_are_probed_in_order() {
+    let root = DocRoot::new("files-probe");
+    root.file("about.html", b"about html\n")
+        .file("about.htm", b"about htm\n")
+        .file("contact.htm", b"contact\n")
+        .file("notes.txt.html", b"not this one\n")
+        .file("docs/index.html", b"docs\n");
+    let server = Server::start(&root, &["--probe-extensions", "html,htm"]);
+    let response = server.exchange(&get("/about"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"about html\n");
+    assert_eq!(
+        header(&response, "Content-Type").as_deref(),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(body(&server.exchange(&get("/contact"))), b"contact\n");
+    // a directory is not a missing file
+    assert_eq!(status(&server.exchange(&get("/docs"))), 301);
+}
+
+#[test]
+fn probing_without_a_match_is_404() {
+    let root = DocRoot::new("files-probe-404");
+    root.file("notes.txt.html", b"not this one\n")
+        .file("about.html", b"about\n");
+    let server = Server::start(&root, &["--probe-extensions", "html"]);
+    assert_eq!(status(&server.exchange(&get("/missing"))), 404);
+    // names that already have an extension are not probed
+    assert_eq!(status(&server.exchange(&get("/notes.txt"))), 404);
+    // nor a path climbing out of the root
+    assert_eq!(status(&server.exchange(&get("/../about"))), 403);
+    let server = Server::start(&root, &[]);
+    assert_eq!(status(&server.exchange(&get("/about"))), 404);
+}
+
+#[test]
+fn save_data_serves_the_light_variant() {
+    let root = DocRoot::new("files-save-data");
+    root.file("photo.jpg", b"full")
+        .file("photo.sd.jpg", b"light")
+        .file("logo.png", b"logo");
+    let server = Server::start(&root, &["--save-data"]);
+    let photo = |extra: &str| {
+        server.exchange(
+            format!(
+                "GET /photo.jpg HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+                extra
+            )
+            .as_bytes(),
+        )
+    };
+    let cases: [(&str, &[u8]); 4] = [
+        ("Save-Data: on\r\n", b"light"),
+        ("save-data: ON\r\n", b"light"),
+        ("Save-Data: off\r\n", b"full"),
+        ("", b"full"),
+    ];
+    for (extra, expected) in cases {
+        let response = photo(extra);
+        assert_eq!(body(&response), expected, "{:?}", extra);
+        // the variant keeps the original's type
+        assert_eq!(
+            header(&response, "Content-Type").as_deref(),
+            Some("image/jpeg")
+        );
+        assert_eq!(header(&response, "Vary").as_deref(), Some("Save-Data"));
+    }
+    // without a variant there is nothing to vary on
+    let response = server.exchange(
+        b"GET /logo.png HTTP/1.1\r\nHost: localhost\r\nSave-Data: on\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(body(&response), b"logo");
+    assert_eq!(header(&response, "Vary"), None);
+}
+
+#[test]
+fn save_data_is_opt_in() {
+    let root = DocRoot::new("files-save-data-off");
+    root.file("photo.jpg", b"full")
+        .file("photo.sd.jpg", b"light");
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(
+        b"GET /photo.jpg HTTP/1.1\r\nHost: localhost\r\nSave-Data: on\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(body(&response), b"full");
+    assert_eq!(header(&response, "Vary"), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn verbose_errors_show_the_detail() {
+    let root = DocRoot::new("files-verbose-errors");
+    root.symlink("a&b", "a&b");
+    let verbose = Server::start(&root, &["--verbose-errors"]);
+    let response = verbose.exchange(&get("/a&b"));
+    assert_eq!(status(&response), 500);
+    let page = String::from_utf8_lossy(body(&response)).into_owned();
+    // the path, escaped for HTML, and the error the OS gave
+    let path = root.path().join("localhost/a&amp;b");
+    assert!(
+        page.contains(&format!("Cannot resolve {}: ", path.display())),
+        "{}",
+        page
+    );
+    assert!(!page.contains("a&b"), "{}", page);
+    assert_eq!(
+        header(&response, "Cache-Control").as_deref(),
+        Some("no-store")
+    );
+
+    let quiet = Server::start(&root, &[]);
+    let response = quiet.exchange(&get("/a&b"));
+    assert_eq!(status(&response), 500);
+    let page = String::from_utf8_lossy(body(&response)).into_owned();
+    assert!(!page.contains("Cannot resolve"), "{}", page);
+    assert!(!page.contains(root.path().to_str().unwrap()), "{}", page);
+}
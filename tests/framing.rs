@@ -0,0 +1,142 @@
+// How the request head and body are delimited: malformed or ambiguous framing is
+// answered with 400 and the connection closed, since what follows cannot be
+// trusted to start a new request.
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+use std::io::{Read, Write};
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("framing");
+    root.file("index.html", b"<h1>hello</h1>\n");
+    root
+}
+
+/// Sends `request` without closing the write side and reads until the server
+/// closes the connection itself.
+fn closed_by_server(server: &Server, request: &[u8]) -> Vec<u8> {
+    let mut stream = server.connect();
+    stream.write_all(request).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    response
+}
+
+fn assert_rejected(response: &[u8], code: u16) {
+    assert_eq!(
+        status(response),
+        code,
+        "{}",
+        String::from_utf8_lossy(response)
+    );
+    assert_eq!(header(response, "Connection").as_deref(), Some("close"));
+}
+
+#[test]
+fn folded_header_is_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nX-Long: first\r\n  second\r\n\r\n",
+    );
+    assert_rejected(&response, 400);
+}
+
+#[test]
+fn duplicate_host_is_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nHost: elsewhere\r\n\r\n",
+    );
+    assert_rejected(&response, 400);
+}
+
+#[test]
+fn length_and_chunked_together_are_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    // read by Content-Length the body is `0\r\n\r\n`, by chunked it is empty and
+    // a second request follows
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    assert_rejected(&response, 400);
+    assert_eq!(response.windows(8).filter(|w| w == b"HTTP/1.1").count(), 1);
+}
+
+#[test]
+fn conflicting_lengths_are_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd",
+    );
+    assert_rejected(&response, 400);
+}
+
+const BARE_LF: &[u8] = b"GET /index.html HTTP/1.1\nHost: localhost\r\nConnection: close\n\n";
+const CRLF: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+#[test]
+fn bare_lf_is_accepted_by_default() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    assert_eq!(status(&server.exchange(BARE_LF)), 200);
+    assert_eq!(status(&server.exchange(CRLF)), 200);
+}
+
+#[test]
+fn bare_lf_is_400_with_strict_crlf() {
+    let root = site();
+    let server = Server::start(&root, &["--strict-crlf"]);
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\n\r\n",
+    );
+    assert_rejected(&response, 400);
+    assert_rejected(&server.exchange(BARE_LF), 400);
+    let response = server.exchange(CRLF);
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"<h1>hello</h1>\n");
+}
+
+const TWO_GETS_WITH_BODIES: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n0123456789GET /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n4;ext=1\r\nabcd\r\n6\r\nefghij\r\n0\r\nX-Trailer: 1\r\n\r\n";
+
+#[test]
+fn bodies_within_the_limit_are_drained() {
+    let root = site();
+    let server = Server::start(&root, &["--max-body-size", "10"]);
+    let wire = server.exchange(TWO_GETS_WITH_BODIES);
+    let text = String::from_utf8_lossy(&wire);
+    // both requests were read where they start, their bodies skipped
+    assert_eq!(text.matches("HTTP/1.1 200 OK\r\n").count(), 2, "{}", text);
+    assert_eq!(text.matches("HTTP/1.1 ").count(), 2, "{}", text);
+}
+
+#[test]
+fn declared_length_over_the_limit_is_413() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    // nothing of the body is sent, the answer must not wait for it
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9999999999\r\n\r\n",
+    );
+    assert_rejected(&response, 413);
+}
+
+#[test]
+fn chunked_body_over_the_limit_is_413() {
+    let root = site();
+    let server = Server::start(&root, &["--max-body-size", "8"]);
+    let response = closed_by_server(
+        &server,
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n6\r\nefghij\r\n",
+    );
+    assert_rejected(&response, 413);
+}
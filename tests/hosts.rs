@@ -0,0 +1,152 @@
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+
+fn get(path: &str, host: &str, extra: &str) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        path, host, extra
+    )
+    .into_bytes()
+}
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("hosts");
+    root.file("page.html", b"<p>page</p>\n");
+    root
+}
+
+#[test]
+fn canonical_host_is_served() {
+    let root = site();
+    let server = Server::start(&root, &["--canonical-host", "localhost"]);
+    let response = server.exchange(&get("/page.html", "localhost", ""));
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"<p>page</p>\n");
+    // the port is not part of the host name
+    let response = server.exchange(&get("/page.html", "localhost:8080", ""));
+    assert_eq!(status(&response), 200);
+}
+
+#[test]
+fn other_hosts_are_redirected_to_the_canonical_one() {
+    let root = site();
+    let server = Server::start(&root, &["--canonical-host", "localhost"]);
+    let response = server.exchange(&get("/page.html?a=1", "www.localhost:8080", ""));
+    assert_eq!(status(&response), 301);
+    assert_eq!(
+        header(&response, "Location").as_deref(),
+        Some("http://localhost:8080/page.html?a=1")
+    );
+    // a client cannot claim a scheme of its own
+    let response = server.exchange(&get(
+        "/page.html",
+        "www.localhost",
+        "X-Forwarded-Proto: https\r\n",
+    ));
+    assert_eq!(
+        header(&response, "Location").as_deref(),
+        Some("http://localhost/page.html")
+    );
+}
+
+#[test]
+fn redirect_keeps_the_scheme_a_trusted_proxy_reports() {
+    let root = site();
+    let server = Server::start(&root, &["--canonical-host", "localhost", "--trust-proxy"]);
+    let https = |protos: &str| {
+        let response = server.exchange(&get(
+            "/page.html",
+            "www.localhost",
+            &format!("X-Forwarded-Proto: {}\r\n", protos),
+        ));
+        header(&response, "Location").unwrap()
+    };
+    assert_eq!(https("https"), "https://localhost/page.html");
+    assert_eq!(https("HTTPS, http"), "https://localhost/page.html");
+    assert_eq!(https("http, https"), "http://localhost/page.html");
+    assert_eq!(https("gopher"), "http://localhost/page.html");
+}
+
+#[test]
+fn empty_host_is_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    for host in ["", "   ", ":8080", "\t"] {
+        let response = server.exchange(&get("/page.html", host, ""));
+        assert_eq!(status(&response), 400, "{:?}", host);
+    }
+}
+
+#[test]
+fn internal_redirect_is_host_checked() {
+    let root = site();
+    root.file("private/report.pdf", b"%PDF-1.7\n");
+    let internal = root.path().join("localhost").join("private");
+    let server = Server::start(
+        &root,
+        &[
+            "--allowed-hosts",
+            "localhost",
+            "--trust-proxy",
+            "--internal-root",
+            internal.to_str().unwrap(),
+        ],
+    );
+    let accel = "X-Accel-Redirect: /report.pdf\r\n";
+    let response = server.exchange(&get("/download", "localhost", accel));
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"%PDF-1.7\n");
+    let response = server.exchange(&get("/download", "evil.example", accel));
+    assert_eq!(status(&response), 421);
+    let response = server.exchange(&get("/download", "", accel));
+    assert_eq!(status(&response), 400);
+}
+
+#[test]
+fn route_is_host_checked() {
+    let root = site();
+    let server = Server::start(
+        &root,
+        &[
+            "--allowed-hosts",
+            "localhost,www.localhost",
+            "--canonical-host",
+            "localhost",
+            "--route",
+            "/robots.txt=text/plain:User-agent: *",
+        ],
+    );
+    let response = server.exchange(&get("/robots.txt", "localhost", ""));
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"User-agent: *");
+    let response = server.exchange(&get("/robots.txt", "evil.example", ""));
+    assert_eq!(status(&response), 421);
+    let response = server.exchange(&get("/robots.txt", "www.localhost", ""));
+    assert_eq!(status(&response), 301);
+    let response = server.exchange(&get("/robots.txt", "", ""));
+    assert_eq!(status(&response), 400);
+}
+
+#[test]
+fn redirect_rule_is_host_checked() {
+    let root = site();
+    let rules = root.path().join("redirects");
+    std::fs::write(&rules, "/old /page.html\n").unwrap();
+    let server = Server::start(
+        &root,
+        &[
+            "--allowed-hosts",
+            "localhost",
+            "--redirects",
+            rules.to_str().unwrap(),
+        ],
+    );
+    let response = server.exchange(&get("/old", "localhost", ""));
+    assert_eq!(status(&response), 301);
+    assert_eq!(header(&response, "Location").as_deref(), Some("/page.html"));
+    let response = server.exchange(&get("/old", "evil.example", ""));
+    assert_eq!(status(&response), 421);
+    let response = server.exchange(&get("/old", "", ""));
+    assert_eq!(status(&response), 400);
+}
@@ -0,0 +1,155 @@
+mod common;
+
+use common::{body, header, status, DocRoot, Server};
+use std::io::{Read, Write};
+
+fn request(line: &str) -> Vec<u8> {
+    format!("{}\r\nHost: localhost\r\nConnection: close\r\n\r\n", line).into_bytes()
+}
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("methods");
+    root.file("index.html", b"<h1>hello</h1>\n");
+    root
+}
+
+#[test]
+fn options_asterisk_is_204_without_length() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&request("OPTIONS * HTTP/1.1"));
+    assert_eq!(status(&response), 204);
+    assert_eq!(header(&response, "Content-Length"), None);
+    assert!(header(&response, "Allow").is_some());
+    assert!(body(&response).is_empty());
+}
+
+#[test]
+fn trace_is_rejected_by_default() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&request("TRACE / HTTP/1.1"));
+    assert_eq!(status(&response), 405);
+    assert!(header(&response, "Allow").is_some_and(|allow| !allow.contains("TRACE")));
+}
+
+#[test]
+fn trace_echoes_the_request_when_enabled() {
+    let root = site();
+    let server = Server::start(&root, &["--enable-trace"]);
+    let response = server.exchange(
+        b"TRACE /x HTTP/1.1\r\nHost: localhost\r\nCookie: secret\r\nX-Probe: 1\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(status(&response), 200);
+    assert_eq!(
+        header(&response, "Content-Type").as_deref(),
+        Some("message/http")
+    );
+    let echo = String::from_utf8_lossy(body(&response)).into_owned();
+    assert!(
+        echo.starts_with("TRACE /x HTTP/1.1\r\nHost: localhost\r\n"),
+        "{}",
+        echo
+    );
+    assert!(echo.contains("X-Probe: 1\r\n"), "{}", echo);
+    // credentials are not reflected, a script reading the echo must not see them
+    assert!(!echo.contains("secret"), "{}", echo);
+}
+
+#[test]
+fn absolute_form_target_is_served() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    // the authority in the target wins over the Host header
+    let response = server.exchange(
+        b"GET http://localhost/index.html HTTP/1.1\r\nHost: elsewhere\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"<h1>hello</h1>\n");
+    let response = server.exchange(&request("HEAD HTTP://localhost:8080/index.html HTTP/1.1"));
+    assert_eq!(status(&response), 200);
+}
+
+#[test]
+fn targets_not_in_origin_form_are_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    for line in [
+        "GET  HTTP/1.1",
+        "GET index.html HTTP/1.1",
+        "GET   HTTP/1.1",
+        "GET http:///index.html HTTP/1.1",
+    ] {
+        assert_eq!(status(&server.exchange(&request(line))), 400, "{}", line);
+    }
+}
+
+#[test]
+fn http09_request_is_400() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    // the client sends nothing after the line, the answer must not wait for headers
+    let mut stream = server.connect();
+    stream.write_all(b"GET /index.html\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(status(&response), 400);
+    assert_eq!(header(&response, "Connection").as_deref(), Some("close"));
+    // other two-token lines are just malformed
+    assert_eq!(status(&server.exchange(&request("HEAD /index.html"))), 400);
+}
+
+#[test]
+fn unknown_expectation_is_417_and_closes() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    // no Connection: close, the server must end the connection itself
+    let mut stream = server.connect();
+    stream
+        .write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nExpect: foo\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(status(&response), 417);
+    assert_eq!(header(&response, "Connection").as_deref(), Some("close"));
+}
+
+#[test]
+fn continue_expectation_proceeds() {
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nExpect: 100-Continue\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), b"<h1>hello</h1>\n");
+}
+
+#[test]
+fn options_advertises_the_precompressed_codings() {
+    let root = site();
+    root.file("app.js", b"let a;\n").file("app.js.gz", b"gz");
+    let server = Server::start(&root, &[]);
+    let response = server.exchange(&request("OPTIONS /app.js HTTP/1.1"));
+    assert_eq!(status(&response), 204);
+    assert_eq!(
+        header(&response, "Accept-Encoding").as_deref(),
+        Some("br, gzip")
+    );
+    // nothing to negotiate without a compressed copy
+    let response = server.exchange(&request("OPTIONS /index.html HTTP/1.1"));
+    assert_eq!(header(&response, "Accept-Encoding"), None);
+}
+
+#[test]
+fn timing_header_covers_the_response_head() {
+    let root = site();
+    let server = Server::start(&root, &["--timing-header"]);
+    let response = server.exchange(&request("GET /index.html HTTP/1.1"));
+    assert_eq!(status(&response), 200);
+    let timing = header(&response, "Server-Timing").unwrap();
+    assert!(timing.starts_with("head;dur="), "{}", timing);
+    let head_time = header(&response, "X-Response-Head-Time").unwrap();
+    assert!(head_time.ends_with("ms"), "{}", head_time);
+    assert_eq!(header(&response, "X-Response-Time"), None);
+}
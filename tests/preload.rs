@@ -0,0 +1,69 @@
+mod common;
+
+use common::{header, status, DocRoot, Server};
+
+const MANIFEST: &str = r#"{ "/index.html": [ { "href": "/style.css", "as": "style" } ],
+  "/docs/": [ { "href": "/docs.js", "as": "script" } ] }"#;
+
+fn get(path: &str) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .into_bytes()
+}
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("preload");
+    root.file("index.html", b"<h1>hello</h1>\n")
+        .file("docs/index.html", b"<h1>docs</h1>\n")
+        .file("style.css", b"h1 {}\n");
+    std::fs::write(root.path().join("preload.json"), MANIFEST).unwrap();
+    root
+}
+
+fn start(root: &DocRoot, options: &[&str]) -> Server {
+    let manifest = root.path().join("preload.json");
+    let mut args = vec!["--preload-manifest", manifest.to_str().unwrap()];
+    args.extend_from_slice(options);
+    Server::start(root, &args)
+}
+
+#[test]
+fn link_header_on_the_page() {
+    let root = site();
+    let server = start(&root, &[]);
+    let link = Some("</style.css>; rel=preload; as=style".to_string());
+    assert_eq!(header(&server.exchange(&get("/index.html")), "Link"), link);
+    // matched by path, as the file itself is found
+    assert_eq!(header(&server.exchange(&get("//index.html")), "Link"), link);
+    assert_eq!(
+        header(&server.exchange(&get("/./index.html#top")), "Link"),
+        link
+    );
+    let docs = server.exchange(&get("/docs/index.html"));
+    assert_eq!(header(&docs, "Link"), None);
+    // not a page, nothing to preload for it
+    assert_eq!(header(&server.exchange(&get("/style.css")), "Link"), None);
+}
+
+#[test]
+fn early_hints_come_first() {
+    let root = site();
+    let server = start(&root, &["--early-hints"]);
+    let response = server.exchange(&get("/index.html"));
+    let text = String::from_utf8_lossy(&response).into_owned();
+    let hints = "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style\r\n\r\n";
+    assert!(text.starts_with(hints), "{}", text);
+    let last = &response[hints.len()..];
+    assert_eq!(status(last), 200);
+    assert!(header(last, "Link").is_some());
+}
+
+#[test]
+fn no_early_hints_for_errors() {
+    let root = site();
+    let server = start(&root, &["--early-hints"]);
+    let response = server.exchange(&get("/missing.html"));
+    assert_eq!(status(&response), 404);
+}
@@ -0,0 +1,89 @@
+#![cfg(unix)]
+mod common;
+
+use common::{status, DocRoot, Server};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+const KEEP_ALIVE: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+// one response read off a keep-alive connection, which stays open
+fn read_response(stream: &mut TcpStream) -> Vec<u8> {
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.ends_with(b"<h1>hello</h1>\n") {
+        let read = stream.read(&mut buf).unwrap();
+        assert!(
+            read > 0,
+            "closed early: {:?}",
+            String::from_utf8_lossy(&response)
+        );
+        response.extend_from_slice(&buf[..read]);
+    }
+    response
+}
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("shutdown");
+    root.file("index.html", b"<h1>hello</h1>\n");
+    root
+}
+
+#[test]
+fn signal_ends_an_idle_accept_promptly() {
+    let root = site();
+    let mut server = Server::start(&root, &[]);
+    let started = Instant::now();
+    server.signal("TERM");
+    let exited = server
+        .wait_exit(Duration::from_secs(2))
+        .expect("server exits");
+    assert!(exited.success());
+    assert!(
+        started.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        started.elapsed()
+    );
+    assert!(server
+        .stdout()
+        .contains("Shutdown complete: 0 connections drained, 0 force-closed."));
+}
+
+#[test]
+fn idle_keep_alive_connections_are_drained() {
+    let root = site();
+    let mut server = Server::start(&root, &[]);
+    let mut connections: Vec<TcpStream> = (0..3).map(|_| server.connect()).collect();
+    for stream in &mut connections {
+        stream.write_all(KEEP_ALIVE).unwrap();
+        assert_eq!(status(&read_response(stream)), 200);
+    }
+
+    let started = Instant::now();
+    server.signal("INT");
+    let exited = server
+        .wait_exit(Duration::from_secs(4))
+        .expect("server exits");
+    // well within the 5 s keep-alive timeout the connections would otherwise hold
+    assert!(
+        started.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        started.elapsed()
+    );
+    assert!(exited.success());
+    for stream in &mut connections {
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+    assert!(
+        server
+            .stdout()
+            .contains("Shutdown complete: 3 connections drained, 0 force-closed."),
+        "{}",
+        server.stdout()
+    );
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{body, status, DocRoot, Server};
+
+fn propfind(path: &str, depth: &str) -> Vec<u8> {
+    format!(
+        "PROPFIND {} HTTP/1.1\r\nHost: localhost\r\nDepth: {}\r\nConnection: close\r\n\r\n",
+        path, depth
+    )
+    .into_bytes()
+}
+
+#[test]
+fn extensionless_file_is_described() {
+    let root = DocRoot::new("webdav-extensionless");
+    root.file("LICENSE", b"MIT\n");
+    let server = Server::start(&root, &["--webdav-readonly"]);
+
+    let response = server.exchange(&propfind("/LICENSE", "0"));
+    assert_eq!(status(&response), 207);
+    let xml = String::from_utf8_lossy(body(&response)).into_owned();
+    assert!(
+        xml.contains("<D:getcontentlength>4</D:getcontentlength>"),
+        "{}",
+        xml
+    );
+    assert!(
+        xml.contains("<D:getcontenttype>application/octet-stream</D:getcontenttype>"),
+        "{}",
+        xml
+    );
+
+    let response = server.exchange(&propfind("/", "1"));
+    assert_eq!(status(&response), 207);
+    let xml = String::from_utf8_lossy(body(&response)).into_owned();
+    assert!(
+        xml.contains("<D:displayname>LICENSE</D:displayname>"),
+        "{}",
+        xml
+    );
+}
+
+#[test]
+fn relative_target_is_only_400_when_propfind_is_served() {
+    let root = DocRoot::new("webdav-relative");
+    root.file("LICENSE", b"MIT\n");
+    let server = Server::start(&root, &[]);
+    let unserved = status(&server.exchange(&propfind("/LICENSE", "0")));
+    assert_ne!(unserved, 400);
+    // without --webdav-readonly the method is answered the same whatever the target
+    assert_eq!(
+        status(&server.exchange(&propfind("LICENSE", "0"))),
+        unserved
+    );
+
+    let server = Server::start(&root, &["--webdav-readonly"]);
+    assert_eq!(status(&server.exchange(&propfind("LICENSE", "0"))), 400);
+}
@@ -0,0 +1,80 @@
+// Golden responses: each `tests/fixtures/wire/<name>.request` is sent as is and
+// what comes back must match `<name>.response` byte for byte, once the `Date`
+// value and the port are normalised. `UPDATE_GOLDEN=1 cargo test --test wire`
+// rewrites the expected responses after an intended change.
+mod common;
+
+use common::{DocRoot, Server};
+use std::{fs, path::PathBuf};
+
+fn site() -> DocRoot {
+    let root = DocRoot::new("wire");
+    root.file("index.html", b"<h1>hello</h1>\n")
+        .file("blob.bin", &[0x00, 0x01, 0x02, 0xff])
+        .dir("dir");
+    #[cfg(unix)]
+    root.symlink("loop", "loop");
+    root
+}
+
+fn golden(name: &str) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wire");
+    let request = fs::read(dir.join(format!("{}.request", name))).unwrap();
+    let root = site();
+    let server = Server::start(&root, &[]);
+    let actual = common::normalise(&server.exchange(&request), server.port());
+    let expected_path = dir.join(format!("{}.response", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&expected_path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read(&expected_path).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&actual),
+        String::from_utf8_lossy(&expected),
+        "{}",
+        name
+    );
+    assert_eq!(actual, expected, "{}", name);
+}
+
+#[test]
+fn ok_html() {
+    golden("ok_html");
+}
+
+#[test]
+fn ok_binary() {
+    golden("ok_binary");
+}
+
+#[test]
+fn moved_permanently() {
+    golden("moved_permanently");
+}
+
+#[test]
+fn bad_request() {
+    golden("bad_request");
+}
+
+#[test]
+fn forbidden() {
+    golden("forbidden");
+}
+
+#[test]
+fn not_found() {
+    golden("not_found");
+}
+
+#[cfg(unix)]
+#[test]
+fn internal_server_error() {
+    golden("internal_server_error");
+}
+
+#[test]
+fn not_implemented() {
+    golden("not_implemented");
+}